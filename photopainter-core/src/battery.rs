@@ -0,0 +1,223 @@
+// Battery voltage thresholds and charge tracking.
+//
+// Battery packs sag differently under the refresh load, so the thresholds are adjustable from the
+// console. Once the battery has been found too low, it has to recover by the hysteresis margin
+// before refreshes resume; otherwise a pack that bounces back a little after every shutdown would
+// be drained by repeated attempts.
+
+use core::fmt;
+
+use crate::datetime::{DateTime, SECONDS_PER_DAY};
+
+/// How worried to be about the battery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Level {
+    Ok,
+    /// Still fine to refresh, but it is time to charge.
+    Warn,
+    /// Too low to refresh safely.
+    Low,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Ok => "OK",
+            Level::Warn => "low, charge soon",
+            Level::Low => "too low to refresh",
+        })
+    }
+}
+
+/// Which threshold a console command adjusts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    Shutdown,
+    Warn,
+    Hysteresis,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Thresholds {
+    /// At or below this, the frame shuts down without refreshing.
+    pub shutdown_millivolts: u32,
+    /// At or below this, the frame still refreshes but warns that the battery is low.
+    pub warn_millivolts: u32,
+    /// How far above the shutdown threshold the battery has to recover after being low.
+    pub hysteresis_millivolts: u32,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            shutdown_millivolts: 3100,
+            warn_millivolts: 3300,
+            hysteresis_millivolts: 200,
+        }
+    }
+}
+
+impl Thresholds {
+    /// Returns true if the thresholds are sensible for a single lithium cell.
+    pub fn is_valid(&self) -> bool {
+        (2500..=4200).contains(&self.shutdown_millivolts)
+            && (self.shutdown_millivolts..=4200).contains(&self.warn_millivolts)
+            && self.hysteresis_millivolts <= 1000
+    }
+
+    /// Returns a copy with one threshold changed, or `None` if the result isn't valid.
+    pub fn with(&self, setting: Setting, millivolts: u32) -> Option<Thresholds> {
+        let mut thresholds = *self;
+        match setting {
+            Setting::Shutdown => thresholds.shutdown_millivolts = millivolts,
+            Setting::Warn => thresholds.warn_millivolts = millivolts,
+            Setting::Hysteresis => thresholds.hysteresis_millivolts = millivolts,
+        }
+        thresholds.is_valid().then_some(thresholds)
+    }
+
+    /// Classifies a battery reading. `was_low` says whether the previous reading was `Low`.
+    pub fn level(&self, millivolts: u32, was_low: bool) -> Level {
+        let shutdown = if was_low {
+            self.shutdown_millivolts + self.hysteresis_millivolts
+        } else {
+            self.shutdown_millivolts
+        };
+        if millivolts <= shutdown {
+            Level::Low
+        } else if millivolts <= self.warn_millivolts {
+            Level::Warn
+        } else {
+            Level::Ok
+        }
+    }
+}
+
+/// Spots the end of a charge cycle from the charger's status output.
+#[derive(Debug, Default)]
+pub struct ChargeMonitor {
+    charging: Option<bool>,
+}
+
+impl ChargeMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds in whether the charger is currently charging. Returns true if it just stopped.
+    pub fn update(&mut self, charging: bool) -> bool {
+        let finished = self.charging == Some(true) && !charging;
+        self.charging = Some(charging);
+        finished
+    }
+}
+
+/// When the battery was last fully charged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FullCharge {
+    /// `None` if the RTC wasn't set at the time.
+    pub at: Option<DateTime>,
+    pub millivolts: u32,
+}
+
+impl FullCharge {
+    /// Whole days between the full charge and `now`, if both times are known.
+    pub fn days_ago(&self, now: &DateTime) -> Option<u32> {
+        let at = self.at?.to_timestamp();
+        Some(now.to_timestamp().checked_sub(at)? / SECONDS_PER_DAY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels() {
+        let thresholds = Thresholds::default();
+        assert_eq!(thresholds.level(3000, false), Level::Low);
+        assert_eq!(thresholds.level(3100, false), Level::Low);
+        assert_eq!(thresholds.level(3101, false), Level::Warn);
+        assert_eq!(thresholds.level(3300, false), Level::Warn);
+        assert_eq!(thresholds.level(4000, false), Level::Ok);
+    }
+
+    #[test]
+    fn hysteresis() {
+        let thresholds = Thresholds::default();
+        assert_eq!(thresholds.level(3250, true), Level::Low);
+        assert_eq!(thresholds.level(3300, true), Level::Low);
+        assert_eq!(thresholds.level(3301, true), Level::Ok);
+        assert_eq!(thresholds.level(3250, false), Level::Warn);
+    }
+
+    #[test]
+    fn settings() {
+        let thresholds = Thresholds::default();
+        assert_eq!(
+            thresholds.with(Setting::Shutdown, 3200),
+            Some(Thresholds {
+                shutdown_millivolts: 3200,
+                ..thresholds
+            })
+        );
+        assert_eq!(
+            thresholds.with(Setting::Hysteresis, 0),
+            Some(Thresholds {
+                hysteresis_millivolts: 0,
+                ..thresholds
+            })
+        );
+        // The warning has to come before the shutdown.
+        assert_eq!(thresholds.with(Setting::Warn, 3000), None);
+        assert_eq!(thresholds.with(Setting::Shutdown, 3400), None);
+        assert_eq!(thresholds.with(Setting::Shutdown, 1000), None);
+        assert_eq!(thresholds.with(Setting::Hysteresis, 5000), None);
+    }
+
+    #[test]
+    fn charge_monitor() {
+        let mut monitor = ChargeMonitor::new();
+        // Not charging at startup doesn't mean a charge just finished.
+        assert!(!monitor.update(false));
+        assert!(!monitor.update(true));
+        assert!(!monitor.update(true));
+        assert!(monitor.update(false));
+        assert!(!monitor.update(false));
+    }
+
+    #[test]
+    fn days_since_full_charge() {
+        let at = DateTime {
+            year: 2024,
+            month: 2,
+            day: 28,
+            hours: 18,
+            minutes: 0,
+            seconds: 0,
+        };
+        let charge = FullCharge {
+            at: Some(at),
+            millivolts: 4150,
+        };
+        assert_eq!(charge.days_ago(&at), Some(0));
+        // Across the leap day.
+        let later = DateTime {
+            month: 3,
+            day: 2,
+            hours: 17,
+            ..at
+        };
+        assert_eq!(charge.days_ago(&later), Some(2));
+        let later = DateTime { hours: 18, ..later };
+        assert_eq!(charge.days_ago(&later), Some(3));
+        // The clock was set back since.
+        let earlier = DateTime { year: 2023, ..at };
+        assert_eq!(charge.days_ago(&earlier), None);
+        let unknown = FullCharge { at: None, ..charge };
+        assert_eq!(unknown.days_ago(&later), None);
+    }
+}
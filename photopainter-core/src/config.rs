@@ -0,0 +1,374 @@
+// Settings kept in the on-chip flash.
+//
+// The RTC's RAM byte only lasts as long as the RTC has power, and it is too small for more than a
+// few flags, so settings changed from the console are saved in flash, at the end of the chip,
+// with `storage::atomic_write`. This module only decides how they are laid out.
+
+use core::fmt;
+
+use crate::battery::{self, Thresholds};
+use crate::image::{DitherMode, Rotation};
+use crate::scheduler::{DisplayMode, Entry, Schedule, TimeOfDay};
+
+/// Size of the saved settings.
+pub const RECORD_LEN: usize = 16;
+
+/// Marks the start of a record, and changes whenever the layout does. New settings can go in bytes
+/// that older records leave as zero without changing it, as long as zero means their default.
+const MAGIC: [u8; 3] = *b"PP\x01";
+
+/// Everything that can be changed with SET.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceConfig {
+    pub dither_mode: DitherMode,
+    pub battery: Thresholds,
+    /// If set, refresh once a day at this time instead of following the schedule file.
+    pub wake_time: Option<TimeOfDay>,
+    /// If set, always show this instead of what the schedule says.
+    pub display_mode: Option<DisplayMode>,
+    pub rotation: Rotation,
+    /// Which set of quotes to show.
+    pub quote_set: u8,
+}
+
+/// A setting, by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Wake,
+    Mode,
+    Rotation,
+    Quotes,
+    Dither,
+    Battery(battery::Setting),
+}
+
+impl Key {
+    /// Every setting, in the order SHOWCFG lists them.
+    pub const ALL: [Key; 8] = [
+        Key::Wake,
+        Key::Mode,
+        Key::Rotation,
+        Key::Quotes,
+        Key::Dither,
+        Key::Battery(battery::Setting::Shutdown),
+        Key::Battery(battery::Setting::Warn),
+        Key::Battery(battery::Setting::Hysteresis),
+    ];
+
+    /// The name used by SET and GET.
+    pub fn name(self) -> &'static str {
+        match self {
+            Key::Wake => "WAKE",
+            Key::Mode => "MODE",
+            Key::Rotation => "ROTATION",
+            Key::Quotes => "QUOTES",
+            Key::Dither => "DITHER",
+            Key::Battery(battery::Setting::Shutdown) => "SHUTDOWN",
+            Key::Battery(battery::Setting::Warn) => "WARN",
+            Key::Battery(battery::Setting::Hysteresis) => "HYSTERESIS",
+        }
+    }
+
+    /// Looks up a setting by name, ignoring case.
+    pub fn find(name: &str) -> Option<Key> {
+        Key::ALL
+            .into_iter()
+            .find(|key| key.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// A new value for one setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    Wake(Option<TimeOfDay>),
+    Mode(Option<DisplayMode>),
+    Rotation(Rotation),
+    Quotes(u8),
+    Dither(DitherMode),
+    /// A battery threshold in millivolts.
+    Battery(battery::Setting, u32),
+}
+
+/// The value of one setting, for showing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    Wake(Option<TimeOfDay>),
+    Mode(Option<DisplayMode>),
+    Rotation(Rotation),
+    Quotes(u8),
+    Dither(DitherMode),
+    Millivolts(u32),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Wake(None) => f.write_str("OFF"),
+            Value::Wake(Some(time)) => write!(f, "{}", time),
+            Value::Mode(None) => f.write_str("AUTO"),
+            Value::Mode(Some(mode)) => write!(f, "{}", mode),
+            Value::Rotation(rotation) => write!(f, "{} degrees", rotation),
+            Value::Quotes(set) => write!(f, "{}", set),
+            Value::Dither(mode) => write!(f, "{}", mode),
+            Value::Millivolts(millivolts) => write!(f, "{} mV", millivolts),
+        }
+    }
+}
+
+impl DeviceConfig {
+    pub fn get(&self, key: Key) -> Value {
+        match key {
+            Key::Wake => Value::Wake(self.wake_time),
+            Key::Mode => Value::Mode(self.display_mode),
+            Key::Rotation => Value::Rotation(self.rotation),
+            Key::Quotes => Value::Quotes(self.quote_set),
+            Key::Dither => Value::Dither(self.dither_mode),
+            Key::Battery(battery::Setting::Shutdown) => {
+                Value::Millivolts(self.battery.shutdown_millivolts)
+            }
+            Key::Battery(battery::Setting::Warn) => Value::Millivolts(self.battery.warn_millivolts),
+            Key::Battery(battery::Setting::Hysteresis) => {
+                Value::Millivolts(self.battery.hysteresis_millivolts)
+            }
+        }
+    }
+
+    /// Returns a copy with one setting changed, or `None` if the result isn't valid.
+    pub fn with(&self, setting: Setting) -> Option<DeviceConfig> {
+        let mut config = *self;
+        match setting {
+            Setting::Wake(time) => config.wake_time = time,
+            Setting::Mode(mode) => config.display_mode = mode,
+            Setting::Rotation(rotation) => config.rotation = rotation,
+            Setting::Quotes(set) => config.quote_set = set,
+            Setting::Dither(mode) => config.dither_mode = mode,
+            Setting::Battery(setting, millivolts) => {
+                config.battery = self.battery.with(setting, millivolts)?;
+            }
+        }
+        Some(config)
+    }
+
+    /// The refreshes to follow, given the ones in the schedule file (or the default ones).
+    pub fn schedule(&self, schedule: &Schedule) -> Schedule {
+        let schedule = match self.wake_time {
+            // Show whatever the schedule would have shown at that time.
+            Some(time) => Schedule::daily(Entry {
+                hours: time.hours,
+                minutes: time.minutes,
+                mode: schedule.mode_at(time),
+            }),
+            None => schedule.clone(),
+        };
+        match self.display_mode {
+            Some(mode) => schedule.with_mode(mode),
+            None => schedule,
+        }
+    }
+
+    /// Lays the settings out as a record.
+    pub fn encode(&self) -> [u8; RECORD_LEN] {
+        let mut record = [0; RECORD_LEN];
+        record[..3].copy_from_slice(&MAGIC);
+        record[3] = self.dither_mode.to_bits();
+        // Thresholds are at most a few volts, so 16 bits are plenty.
+        let thresholds = [
+            self.battery.shutdown_millivolts,
+            self.battery.warn_millivolts,
+            self.battery.hysteresis_millivolts,
+        ];
+        for (bytes, millivolts) in record[4..10].chunks_exact_mut(2).zip(thresholds) {
+            bytes.copy_from_slice(&(millivolts as u16).to_le_bytes());
+        }
+        // Records from before these settings have zeros here, so zero is the default for each.
+        if let Some(time) = self.wake_time {
+            record[10] = time.hours + 1;
+            record[11] = time.minutes;
+        }
+        record[12] = self.display_mode.map_or(0, |mode| mode.to_bits() + 1);
+        record[13] = self.rotation.to_bits();
+        record[14] = self.quote_set;
+        record
+    }
+
+    /// Reads a record back, or returns `None` if it doesn't hold valid settings.
+    pub fn decode(record: &[u8]) -> Option<DeviceConfig> {
+        if record.len() != RECORD_LEN || record[..3] != MAGIC {
+            return None;
+        }
+        let millivolts = |i: usize| u16::from_le_bytes([record[i], record[i + 1]]) as u32;
+        let wake_time = match record[10] {
+            0 => None,
+            hours if hours <= 24 && record[11] < 60 => Some(TimeOfDay {
+                hours: hours - 1,
+                minutes: record[11],
+            }),
+            _ => return None,
+        };
+        let display_mode = match record[12] {
+            0 => None,
+            bits => Some(DisplayMode::from_bits(bits - 1)?),
+        };
+        let config = DeviceConfig {
+            dither_mode: DitherMode::from_bits(record[3])?,
+            battery: Thresholds {
+                shutdown_millivolts: millivolts(4),
+                warn_millivolts: millivolts(6),
+                hysteresis_millivolts: millivolts(8),
+            },
+            wake_time,
+            display_mode,
+            rotation: Rotation::from_bits(record[13])?,
+            quote_set: record[14],
+        };
+        config.battery.is_valid().then_some(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    fn seven_thirty() -> TimeOfDay {
+        TimeOfDay {
+            hours: 7,
+            minutes: 30,
+        }
+    }
+
+    fn changed() -> DeviceConfig {
+        [
+            Setting::Dither(DitherMode::Atkinson),
+            Setting::Battery(battery::Setting::Warn, 3450),
+            Setting::Wake(Some(seven_thirty())),
+            Setting::Mode(Some(DisplayMode::Art)),
+            Setting::Rotation(Rotation::ThreeQuarters),
+            Setting::Quotes(3),
+        ]
+        .into_iter()
+        .try_fold(DeviceConfig::default(), |config, setting| {
+            config.with(setting)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let midnight = DeviceConfig {
+            wake_time: Some(TimeOfDay {
+                hours: 0,
+                minutes: 0,
+            }),
+            display_mode: Some(DisplayMode::Calendar),
+            ..DeviceConfig::default()
+        };
+        for config in [DeviceConfig::default(), changed(), midnight] {
+            assert_eq!(DeviceConfig::decode(&config.encode()), Some(config));
+        }
+        assert_eq!(DeviceConfig::decode(&[0xFF; RECORD_LEN]), None);
+        assert_eq!(DeviceConfig::decode(&[0; RECORD_LEN]), None);
+
+        // A record saved before the wake time, mode, rotation and quote set were added.
+        let mut record = [0; RECORD_LEN];
+        record[..3].copy_from_slice(b"PP\x01");
+        record[3] = DitherMode::Atkinson.to_bits();
+        record[4..10].copy_from_slice(&changed().encode()[4..10]);
+        assert_eq!(
+            DeviceConfig::decode(&record),
+            Some(DeviceConfig {
+                dither_mode: DitherMode::Atkinson,
+                battery: changed().battery,
+                ..DeviceConfig::default()
+            })
+        );
+
+        // Thresholds that BATTERY wouldn't accept.
+        let mut record = changed().encode();
+        record[4..6].copy_from_slice(&5000u16.to_le_bytes());
+        assert_eq!(DeviceConfig::decode(&record), None);
+        // Or a dither mode that doesn't exist.
+        let mut record = changed().encode();
+        record[3] = 7;
+        assert_eq!(DeviceConfig::decode(&record), None);
+        // Or a wake time, mode or rotation that doesn't exist.
+        for (i, bits) in [(10, 25), (11, 60), (12, 4), (13, 4)] {
+            let mut record = changed().encode();
+            record[i] = bits;
+            assert_eq!(DeviceConfig::decode(&record), None, "{}", i);
+        }
+    }
+
+    #[test]
+    fn settings() {
+        let config = changed();
+        assert_eq!(config.get(Key::Dither), Value::Dither(DitherMode::Atkinson));
+        assert_eq!(
+            config.get(Key::Battery(battery::Setting::Warn)),
+            Value::Millivolts(3450)
+        );
+        assert_eq!(
+            config.with(Setting::Battery(battery::Setting::Shutdown, 3500)),
+            None
+        );
+        for key in Key::ALL {
+            assert_eq!(Key::find(key.name()), Some(key));
+        }
+        assert_eq!(Key::find("hysteresis"), Some(Key::ALL[7]));
+        assert_eq!(Key::find("BRIGHTNESS"), None);
+        assert_eq!(config.get(Key::Wake), Value::Wake(Some(seven_thirty())));
+        assert_eq!(config.get(Key::Quotes), Value::Quotes(3));
+    }
+
+    #[test]
+    fn shown_values() {
+        let shown = |key| std::format!("{}", changed().get(key));
+        assert_eq!(shown(Key::Wake), "07:30");
+        assert_eq!(shown(Key::Mode), "art");
+        assert_eq!(shown(Key::Rotation), "270 degrees");
+        let defaults = DeviceConfig::default();
+        assert_eq!(std::format!("{}", defaults.get(Key::Wake)), "OFF");
+        assert_eq!(std::format!("{}", defaults.get(Key::Mode)), "AUTO");
+    }
+
+    #[test]
+    fn schedules() {
+        let schedule = Schedule::default();
+        assert_eq!(DeviceConfig::default().schedule(&schedule), schedule);
+
+        // Once a day, showing what the schedule would have at 07:30.
+        let wake = DeviceConfig {
+            wake_time: Some(seven_thirty()),
+            ..DeviceConfig::default()
+        };
+        assert_eq!(
+            wake.schedule(&schedule).entries(),
+            &[Entry {
+                hours: 7,
+                minutes: 30,
+                mode: DisplayMode::Calendar,
+            }]
+        );
+        assert_eq!(
+            changed().schedule(&schedule).entries(),
+            &[Entry {
+                hours: 7,
+                minutes: 30,
+                mode: DisplayMode::Art,
+            }]
+        );
+
+        let art = DeviceConfig {
+            display_mode: Some(DisplayMode::Art),
+            ..DeviceConfig::default()
+        };
+        let entries = art.schedule(&schedule);
+        assert_eq!(entries.entries().len(), schedule.entries().len());
+        assert!(entries
+            .entries()
+            .iter()
+            .all(|entry| entry.mode == DisplayMode::Art));
+    }
+}
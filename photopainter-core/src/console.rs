@@ -0,0 +1,1461 @@
+// Console command parsing and line editing.
+//
+// This is kept separate from the serial transport so that it can be tested, and fuzzed, on the
+// host.
+
+use core::fmt;
+
+#[cfg(not(feature = "kiosk"))]
+use crate::battery;
+use crate::config;
+#[cfg(not(feature = "kiosk"))]
+use crate::config::Setting;
+use crate::datetime::DateTime;
+#[cfg(not(feature = "kiosk"))]
+use crate::frame::Encoding;
+#[cfg(not(feature = "kiosk"))]
+use crate::image::{DitherMode, Rotation};
+#[cfg(not(feature = "kiosk"))]
+use crate::scheduler::{DisplayMode, TimeOfDay};
+
+pub const MAX_LINE_LENGTH: usize = 80;
+
+/// Number of lines of HELP output shown before pausing at `--more--`. Small enough for a
+/// terminal window that has been left at its smallest.
+pub const PAGE_LINES: usize = 10;
+
+/// Width of the usage column in the HELP summary.
+pub const USAGE_WIDTH: usize = 34;
+
+/// Longest interval MONITOR accepts: one status line a day.
+pub const MAX_MONITOR_INTERVAL_SECONDS: u32 = 24 * 60 * 60;
+
+/// Most arguments any command takes.
+pub const MAX_ARGS: usize = 4;
+
+/// Describes one console command: how to parse it, and its HELP text.
+///
+/// Adding a command means adding a `Command` variant, an entry here, and an arm in the firmware's
+/// command handler.
+#[derive(Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    /// Range of argument counts accepted; anything else is rejected before `parse` is called.
+    pub min_args: usize,
+    pub max_args: usize,
+    /// Turns the arguments into a command, or returns `None` if they aren't valid.
+    pub parse: fn(&[&str]) -> Option<Command>,
+    pub usage: &'static str,
+    /// One line for the summary list.
+    pub summary: &'static str,
+    /// Shown by `HELP <command>`.
+    pub details: &'static str,
+}
+
+// Commands are identified by name; comparing the parse functions would be meaningless.
+impl PartialEq for CommandSpec {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for CommandSpec {}
+
+/// Every console command, in the order HELP lists them.
+///
+/// The `kiosk` feature leaves out the commands that change the clock, the settings or files, or
+/// that power off or restart the frame, for frames on display where anyone can reach the USB
+/// port. BATTERY and DITHER are still there, but only show the settings.
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "HELP",
+        min_args: 0,
+        max_args: 1,
+        parse: |args| match args {
+            [] => Some(Command::Help(None)),
+            [topic] => find_command(topic).map(|command| Command::Help(Some(command))),
+            _ => None,
+        },
+        usage: "HELP [COMMAND]",
+        summary: "Show this help, or details of a command",
+        details: "\
+Without an argument, lists all commands. Long output stops at --more--;
+press any key for the next page, or Q to stop.
+Example: HELP BATTERY",
+    },
+    CommandSpec {
+        name: "STATUS",
+        min_args: 0,
+        max_args: 0,
+        parse: |_| Some(Command::Status),
+        usage: "STATUS",
+        summary: "Show power and temperature readings",
+        details: "\
+Shows whether USB power is present, whether the battery is charging, the
+battery voltage, whether it was low at power-on (compared with the WARN and
+SHUTDOWN settings), the chip temperature, and how long the frame stayed awake
+the last time it ran and whether that was a fast boot from the RTC alarm.",
+    },
+    CommandSpec {
+        name: "TEMP",
+        min_args: 0,
+        max_args: 0,
+        parse: |_| Some(Command::Temp),
+        usage: "TEMP",
+        summary: "Show the chip temperature",
+        details: "\
+Shows the temperature of the RP2040, from its internal sensor, in degrees
+Celsius. It reads a few degrees above the room while the frame is busy.",
+    },
+    CommandSpec {
+        name: "TIME",
+        min_args: 0,
+        max_args: 0,
+        parse: |_| Some(Command::Time),
+        usage: "TIME",
+        summary: "Show the RTC date and time",
+        details: "Shows the RTC date and time, or an error if the clock has not been set.",
+    },
+    #[cfg(not(feature = "kiosk"))]
+    CommandSpec {
+        name: "SETTIME",
+        min_args: 2,
+        max_args: 2,
+        parse: |args| parse_datetime(args[0], args[1]).map(Command::SetTime),
+        usage: "SETTIME YYYY-MM-DD HH:MM:SS",
+        summary: "Set the RTC date and time",
+        details: "\
+Sets the RTC date and time, using a 24-hour clock. Years 2000 to 2099 only.
+Example: SETTIME 2024-12-24 18:30:00",
+    },
+    #[cfg(not(feature = "kiosk"))]
+    CommandSpec {
+        name: "BATTERY",
+        min_args: 0,
+        max_args: 2,
+        parse: |args| match args {
+            [] => Some(Command::Battery),
+            [threshold, millivolts] => Some(Command::SetBattery(
+                parse_battery_setting(threshold)?,
+                millivolts.parse().ok()?,
+            )),
+            _ => None,
+        },
+        usage: "BATTERY [<THRESHOLD> <MV>]",
+        summary: "Show or set the battery thresholds",
+        details: "\
+Without arguments, shows the battery voltage, the thresholds and the last
+full charge. With arguments, sets one threshold in millivolts and saves it:
+  SHUTDOWN    At or below this, the frame turns off without refreshing
+  WARN        At or below this, the frame warns that the battery is low
+  HYSTERESIS  How far the battery must recover after a shutdown
+Example: BATTERY WARN 3350",
+    },
+    #[cfg(feature = "kiosk")]
+    CommandSpec {
+        name: "BATTERY",
+        min_args: 0,
+        max_args: 0,
+        parse: |_| Some(Command::Battery),
+        usage: "BATTERY",
+        summary: "Show the battery thresholds",
+        details: "Shows the battery voltage, the thresholds and the last full charge.",
+    },
+    #[cfg(not(feature = "kiosk"))]
+    CommandSpec {
+        name: "SLEEPUNTIL",
+        min_args: 1,
+        max_args: 1,
+        parse: |args| parse_timestamp(args[0]).map(Command::SleepUntil),
+        usage: "SLEEPUNTIL YYYY-MM-DDTHH:MM[:SS]",
+        summary: "Power off until the given time",
+        details: "\
+Sets the RTC alarm for the given time and powers off. On USB power the frame
+stays idle instead, until the alarm or the button wakes it up. The alarm can
+be at most 28 days ahead.
+Example: SLEEPUNTIL 2025-12-24T07:00",
+    },
+    CommandSpec {
+        name: "PLAN",
+        min_args: 0,
+        max_args: 1,
+        parse: |args| match args {
+            [] => Some(Command::Plan(None)),
+            [timestamp] => parse_timestamp(timestamp).map(|wake| Command::Plan(Some(wake))),
+            _ => None,
+        },
+        usage: "PLAN [YYYY-MM-DDTHH:MM[:SS]]",
+        summary: "Show what the RTC alarm would be set to",
+        details: "\
+Shows the wake-up times and alarm register values that the next shutdown,
+or SLEEPUNTIL with the given time, would program. Nothing is changed.
+Example: PLAN 2025-12-24T07:00",
+    },
+    CommandSpec {
+        name: "TERM",
+        min_args: 0,
+        max_args: 2,
+        parse: |args| match args {
+            [] => Some(Command::Term(None)),
+            [setting, value] => Some(Command::Term(Some((
+                parse_term_setting(setting)?,
+                parse_on_off(value)?,
+            )))),
+            _ => None,
+        },
+        usage: "TERM [<SETTING> ON|OFF]",
+        summary: "Show or change the terminal settings",
+        details: "\
+Without arguments, shows the terminal settings. With arguments, changes one:
+  ECHO  Echo typed characters back (turn off when piping in a script)
+  CRLF  End output lines with CR LF rather than just LF
+  ANSI  Use color and bold text to make responses easier to read
+Example: TERM ECHO OFF",
+    },
+    CommandSpec {
+        name: "MONITOR",
+        min_args: 0,
+        max_args: 1,
+        parse: |args| match args {
+            [] => Some(Command::Monitor),
+            [value] if value.eq_ignore_ascii_case("OFF") => Some(Command::SetMonitor(None)),
+            [value] => value
+                .parse()
+                .ok()
+                .filter(|seconds| (1..=MAX_MONITOR_INTERVAL_SECONDS).contains(seconds))
+                .map(|seconds| Command::SetMonitor(Some(seconds))),
+            _ => None,
+        },
+        usage: "MONITOR [<SECONDS>|OFF]",
+        summary: "Print status lines while the console is idle",
+        details: "\
+Prints a one-line status (time, battery, charging and state) whenever no
+command has been entered for the given number of seconds, for logging the
+frame from another machine. Without arguments, shows the current interval.
+Example: MONITOR 60",
+    },
+    CommandSpec {
+        name: "LS",
+        min_args: 0,
+        max_args: 1,
+        parse: |args| match args {
+            [] => Some(Command::Ls(None)),
+            [dir] => FileName::parse(dir)
+                .filter(|dir| dir.dir().is_none())
+                .map(|dir| Command::Ls(Some(dir))),
+            _ => None,
+        },
+        usage: "LS [DIR]",
+        summary: "List the files on the SD card",
+        details: "\
+Lists the files in the root directory of the SD card, or in the given
+directory, with their sizes.
+Example: LS IMAGES",
+    },
+    CommandSpec {
+        name: "CAT",
+        min_args: 1,
+        max_args: 1,
+        parse: |args| FileName::parse(args[0]).map(Command::Cat),
+        usage: "CAT <FILE>",
+        summary: "Print a file from the SD card",
+        details: "\
+Prints a file from the SD card. Only 8.3 file names are supported, at most
+one directory deep.
+Example: CAT IMAGES/INDEX.TXT",
+    },
+    #[cfg(not(feature = "kiosk"))]
+    CommandSpec {
+        name: "UPLOAD",
+        min_args: 1,
+        max_args: 1,
+        parse: |args| FileName::parse(args[0]).map(Command::Upload),
+        usage: "UPLOAD <FILE>",
+        summary: "Receive a file onto the SD card",
+        details: "\
+Receives a file with YMODEM or XMODEM-1K and saves it on the SD card,
+replacing any file with the same name. Start the transfer from the
+terminal once the C characters appear; press Ctrl-X twice to give up.
+Example: UPLOAD IMAGES/PHOTO1.BIN",
+    },
+    #[cfg(not(feature = "kiosk"))]
+    CommandSpec {
+        name: "PUSHFB",
+        min_args: 1,
+        max_args: 2,
+        parse: |args| {
+            let encoding = match args {
+                [_] => Encoding::Raw,
+                [_, encoding] if encoding.eq_ignore_ascii_case("RLE") => Encoding::Rle,
+                _ => return None,
+            };
+            let len = args[0].parse().ok()?;
+            encoding
+                .is_valid_length(len)
+                .then_some(Command::PushFb(len, encoding))
+        },
+        usage: "PUSHFB <LEN> [RLE]",
+        summary: "Receive and check a frame buffer",
+        details: "\
+Receives LEN bytes of panel frame buffer (two 4-bit colors per byte, 400
+bytes per row) and checks them. Uncompressed, LEN must be 192000. With RLE,
+the data is pairs of a repeat count (1-255) and the byte to repeat. Send the
+data as it is, once the prompt to start appears. There is no display driver
+yet, so a good frame gets an error saying so rather than being shown.
+Example: PUSHFB 192000",
+    },
+    #[cfg(not(feature = "kiosk"))]
+    CommandSpec {
+        name: "DITHER",
+        min_args: 0,
+        max_args: 1,
+        parse: |args| match args {
+            [] => Some(Command::Dither),
+            [mode] => parse_dither_mode(mode).map(Command::SetDither),
+            _ => None,
+        },
+        usage: "DITHER [<MODE>]",
+        summary: "Show or pick how BMP images are dithered",
+        details: "\
+Without an argument, shows how BMP images are dithered. With one, picks:
+  NONE      Use the nearest color; best for flat-colored drawings
+  ORDERED   Add a fixed 4x4 pattern, like printed halftone
+  FLOYD     Floyd-Steinberg error diffusion (the default)
+  ATKINSON  Error diffusion with more contrast
+The choice is saved in flash.
+Example: DITHER ATKINSON",
+    },
+    #[cfg(feature = "kiosk")]
+    CommandSpec {
+        name: "DITHER",
+        min_args: 0,
+        max_args: 0,
+        parse: |_| Some(Command::Dither),
+        usage: "DITHER",
+        summary: "Show how BMP images are dithered",
+        details: "Shows how BMP images are dithered.",
+    },
+    #[cfg(not(feature = "kiosk"))]
+    CommandSpec {
+        name: "SET",
+        min_args: 2,
+        max_args: 2,
+        parse: |args| {
+            let value = args[1];
+            let setting = match config::Key::find(args[0])? {
+                config::Key::Wake if value.eq_ignore_ascii_case("OFF") => Setting::Wake(None),
+                config::Key::Wake => Setting::Wake(Some(TimeOfDay::parse(value)?)),
+                config::Key::Mode if value.eq_ignore_ascii_case("AUTO") => Setting::Mode(None),
+                config::Key::Mode => Setting::Mode(Some(DisplayMode::find(value)?)),
+                config::Key::Rotation => {
+                    Setting::Rotation(Rotation::from_degrees(value.parse().ok()?)?)
+                }
+                config::Key::Quotes => Setting::Quotes(value.parse().ok()?),
+                config::Key::Dither => Setting::Dither(parse_dither_mode(value)?),
+                config::Key::Battery(threshold) => Setting::Battery(threshold, value.parse().ok()?),
+            };
+            Some(Command::Set(setting))
+        },
+        usage: "SET <KEY> <VALUE>",
+        summary: "Change a setting and save it in flash",
+        details: "\
+Changes one setting and saves it in flash, where it survives the battery
+running flat. The settings are:
+  WAKE        Refresh once a day at HH:MM, instead of the schedule; or OFF
+  MODE        Always show CALENDAR, SLIDESHOW or ART; or AUTO, as scheduled
+  ROTATION    Turn pictures 0, 90, 180 or 270 degrees clockwise
+  QUOTES      Which set of quotes to show (0-255)
+  DITHER      How BMP images are dithered (see HELP DITHER)
+  SHUTDOWN    Battery threshold in millivolts (see HELP BATTERY)
+  WARN        Battery threshold in millivolts
+  HYSTERESIS  Battery threshold in millivolts
+Example: SET WARN 3350",
+    },
+    CommandSpec {
+        name: "GET",
+        min_args: 1,
+        max_args: 1,
+        parse: |args| config::Key::find(args[0]).map(Command::Get),
+        usage: "GET <KEY>",
+        summary: "Show a setting",
+        details: "\
+Shows one of the settings that SET changes.
+Example: GET DITHER",
+    },
+    CommandSpec {
+        name: "SHOWCFG",
+        min_args: 0,
+        max_args: 0,
+        parse: |_| Some(Command::ShowConfig),
+        usage: "SHOWCFG",
+        summary: "Show all the settings",
+        details: "Shows all the settings that SET changes.",
+    },
+    #[cfg(not(feature = "kiosk"))]
+    CommandSpec {
+        name: "RESET",
+        min_args: 0,
+        max_args: 0,
+        parse: |_| Some(Command::Reset),
+        usage: "RESET",
+        summary: "Restart the firmware",
+        details: "Restarts the firmware, as if the reset button had been pressed.",
+    },
+    #[cfg(not(feature = "kiosk"))]
+    CommandSpec {
+        name: "DFU",
+        min_args: 0,
+        max_args: 0,
+        parse: |_| Some(Command::Dfu),
+        usage: "DFU",
+        summary: "Reboot into the USB bootloader",
+        details: "\
+Reboots into the RP2040 USB bootloader, so that new firmware can be copied
+to the RPI-RP2 drive.",
+    },
+];
+
+/// Looks up a command by name, ignoring case.
+pub fn find_command(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS
+        .iter()
+        .find(|command| command.name.eq_ignore_ascii_case(name))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Lists the commands, or describes one of them.
+    Help(Option<&'static CommandSpec>),
+    Status,
+    Temp,
+    Time,
+    #[cfg(not(feature = "kiosk"))]
+    SetTime(DateTime),
+    Battery,
+    #[cfg(not(feature = "kiosk"))]
+    SetBattery(battery::Setting, u32),
+    #[cfg(not(feature = "kiosk"))]
+    SleepUntil(DateTime),
+    /// Shows what the alarm would be set to, optionally for a SLEEPUNTIL time.
+    Plan(Option<DateTime>),
+    /// Shows the terminal settings, or changes one of them.
+    Term(Option<(TermSetting, bool)>),
+    Monitor,
+    /// Sets the idle status interval in seconds, or turns it off.
+    SetMonitor(Option<u32>),
+    /// Lists the root directory, or the given directory.
+    Ls(Option<FileName>),
+    Cat(FileName),
+    #[cfg(not(feature = "kiosk"))]
+    Upload(FileName),
+    /// Receives a frame buffer of the given length over the console.
+    #[cfg(not(feature = "kiosk"))]
+    PushFb(u32, Encoding),
+    Dither,
+    #[cfg(not(feature = "kiosk"))]
+    SetDither(DitherMode),
+    #[cfg(not(feature = "kiosk"))]
+    Set(Setting),
+    Get(config::Key),
+    ShowConfig,
+    #[cfg(not(feature = "kiosk"))]
+    Reset,
+    #[cfg(not(feature = "kiosk"))]
+    Dfu,
+}
+
+/// How the console talks to the terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TermMode {
+    /// Echo input back as it is typed.
+    pub echo: bool,
+    /// Expand output newlines to CRLF.
+    pub crlf: bool,
+    /// Use ANSI escape sequences for color.
+    pub ansi: bool,
+}
+
+impl Default for TermMode {
+    /// Suits an interactive terminal emulator, without assuming it understands ANSI.
+    fn default() -> Self {
+        TermMode {
+            echo: true,
+            crlf: true,
+            ansi: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TermSetting {
+    Echo,
+    Crlf,
+    Ansi,
+}
+
+impl TermMode {
+    pub fn set(&mut self, setting: TermSetting, on: bool) {
+        match setting {
+            TermSetting::Echo => self.echo = on,
+            TermSetting::Crlf => self.crlf = on,
+            TermSetting::Ansi => self.ansi = on,
+        }
+    }
+}
+
+/// A file on the FAT formatted SD card: an 8.3 name, optionally in a directory below the root
+/// (e.g. `IMAGES/PHOTO.BIN`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileName {
+    bytes: [u8; FileName::MAX_LENGTH],
+    len: u8,
+}
+
+impl FileName {
+    pub const MAX_LENGTH: usize = 25;
+
+    /// Checks that `path` is an 8.3 file name, with at most one directory in front of it.
+    pub fn parse(path: &str) -> Option<FileName> {
+        let (dir, name) = match path.split_once('/') {
+            Some((dir, name)) => (Some(dir), name),
+            None => (None, path),
+        };
+        if !is_short_name(name) || dir.is_some_and(|dir| !is_short_name(dir)) {
+            return None;
+        }
+        let mut bytes = [0; FileName::MAX_LENGTH];
+        bytes[..path.len()].copy_from_slice(path.as_bytes());
+        Some(FileName {
+            bytes,
+            len: path.len() as u8,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ASCII gets past `parse`.
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or_default()
+    }
+
+    /// The directory the file is in, or `None` for the root directory.
+    pub fn dir(&self) -> Option<&str> {
+        self.as_str().split_once('/').map(|(dir, _)| dir)
+    }
+
+    /// The name of the file within its directory.
+    pub fn name(&self) -> &str {
+        let path = self.as_str();
+        path.split_once('/').map_or(path, |(_, name)| name)
+    }
+}
+
+/// Returns true if `name` is a valid 8.3 name.
+fn is_short_name(name: &str) -> bool {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    let valid_chars = |part: &str| {
+        part.bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"!#$%&'()-@^_`{}~".contains(&c))
+    };
+    (1..=8).contains(&base.len())
+        && extension.len() <= 3
+        && valid_chars(base)
+        && valid_chars(extension)
+}
+
+impl fmt::Display for FileName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The input line was longer than the console buffer.
+    LineTooLong,
+    /// The first word isn't a known command.
+    UnknownCommand,
+    /// The command was recognized, but its arguments weren't.
+    InvalidArguments,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::LineTooLong => f.write_str("line too long"),
+            ParseError::UnknownCommand => f.write_str("unknown command (try HELP)"),
+            ParseError::InvalidArguments => f.write_str("invalid arguments (try HELP)"),
+        }
+    }
+}
+
+/// Parses a single line of console input.
+pub fn parse_command(line: &str) -> Result<Command, ParseError> {
+    let mut words = line.split_whitespace();
+    let name = words.next().ok_or(ParseError::UnknownCommand)?;
+    let spec = find_command(name).ok_or(ParseError::UnknownCommand)?;
+    let mut args = heapless::Vec::<&str, MAX_ARGS>::new();
+    for word in words {
+        args.push(word).map_err(|_| ParseError::InvalidArguments)?;
+    }
+    if args.len() < spec.min_args || args.len() > spec.max_args {
+        return Err(ParseError::InvalidArguments);
+    }
+    (spec.parse)(&args).ok_or(ParseError::InvalidArguments)
+}
+
+/// Parses a `YYYY-MM-DD` date and `HH:MM:SS` time.
+#[cfg(not(feature = "kiosk"))]
+fn parse_datetime(date: &str, time: &str) -> Option<DateTime> {
+    parse_date_and_time(date, time, false)
+}
+
+/// Parses an ISO 8601 style `YYYY-MM-DDTHH:MM[:SS]` timestamp.
+fn parse_timestamp(timestamp: &str) -> Option<DateTime> {
+    let (date, time) = timestamp.split_once(['T', 't'])?;
+    parse_date_and_time(date, time, true)
+}
+
+fn parse_date_and_time(date: &str, time: &str, seconds_optional: bool) -> Option<DateTime> {
+    let mut date = date.split('-');
+    let mut time = time.split(':');
+    let datetime = DateTime {
+        year: date.next()?.parse().ok()?,
+        month: date.next()?.parse().ok()?,
+        day: date.next()?.parse().ok()?,
+        hours: time.next()?.parse().ok()?,
+        minutes: time.next()?.parse().ok()?,
+        seconds: match time.next() {
+            Some(seconds) => seconds.parse().ok()?,
+            None if seconds_optional => 0,
+            None => return None,
+        },
+    };
+    if date.next().is_some() || time.next().is_some() || !datetime.is_valid() {
+        return None;
+    }
+    Some(datetime)
+}
+
+#[cfg(not(feature = "kiosk"))]
+fn parse_battery_setting(name: &str) -> Option<battery::Setting> {
+    if name.eq_ignore_ascii_case("SHUTDOWN") {
+        Some(battery::Setting::Shutdown)
+    } else if name.eq_ignore_ascii_case("WARN") {
+        Some(battery::Setting::Warn)
+    } else if name.eq_ignore_ascii_case("HYSTERESIS") {
+        Some(battery::Setting::Hysteresis)
+    } else {
+        None
+    }
+}
+
+fn parse_term_setting(name: &str) -> Option<TermSetting> {
+    if name.eq_ignore_ascii_case("ECHO") {
+        Some(TermSetting::Echo)
+    } else if name.eq_ignore_ascii_case("CRLF") {
+        Some(TermSetting::Crlf)
+    } else if name.eq_ignore_ascii_case("ANSI") {
+        Some(TermSetting::Ansi)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(feature = "kiosk"))]
+fn parse_dither_mode(name: &str) -> Option<DitherMode> {
+    DitherMode::ALL
+        .into_iter()
+        .find(|mode| mode.name().eq_ignore_ascii_case(name))
+}
+
+fn parse_on_off(value: &str) -> Option<bool> {
+    if value.eq_ignore_ascii_case("ON") {
+        Some(true)
+    } else if value.eq_ignore_ascii_case("OFF") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Splits the HELP summary into pages of `PAGE_LINES` lines, with a `--more--` prompt between
+/// them.
+#[derive(Debug, Default)]
+pub struct HelpPager {
+    /// Commands not listed yet.
+    rest: &'static [CommandSpec],
+}
+
+impl HelpPager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts listing `commands`. Returns the first page, which leaves a line for a heading.
+    pub fn start(&mut self, commands: &'static [CommandSpec]) -> &'static [CommandSpec] {
+        self.rest = commands;
+        self.take(PAGE_LINES - 1)
+    }
+
+    /// Returns true while waiting at the `--more--` prompt.
+    pub fn is_paused(&self) -> bool {
+        !self.rest.is_empty()
+    }
+
+    /// Handles a key pressed at the prompt. Returns the next page, which is empty if the key was
+    /// Q, or `None` if the key should be ignored.
+    pub fn key(&mut self, byte: u8) -> Option<&'static [CommandSpec]> {
+        // Skip the LF of a CRLF line ending, which would otherwise skip the first page.
+        if byte == b'\n' {
+            return None;
+        }
+        if byte.eq_ignore_ascii_case(&b'q') {
+            self.rest = &[];
+            return Some(&[]);
+        }
+        Some(self.take(PAGE_LINES))
+    }
+
+    fn take(&mut self, lines: usize) -> &'static [CommandSpec] {
+        let (page, rest) = self.rest.split_at(lines.min(self.rest.len()));
+        self.rest = rest;
+        page
+    }
+}
+
+/// Decides when to print an unsolicited status line, for MONITOR.
+#[derive(Debug, Default)]
+pub struct IdleMonitor {
+    interval_ms: Option<u64>,
+    last_ms: u64,
+}
+
+impl IdleMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn interval_seconds(&self) -> Option<u32> {
+        self.interval_ms.map(|ms| (ms / 1000) as u32)
+    }
+
+    /// Sets the interval, or turns the status lines off. The first line is due one interval from
+    /// `now_ms`.
+    pub fn set_interval(&mut self, seconds: Option<u32>, now_ms: u64) {
+        self.interval_ms = seconds.map(|seconds| seconds as u64 * 1000);
+        self.last_ms = now_ms;
+    }
+
+    /// Notes that a command was entered, which postpones the next status line.
+    pub fn activity(&mut self, now_ms: u64) {
+        self.last_ms = now_ms;
+    }
+
+    /// Returns true if a status line is due now.
+    pub fn poll(&mut self, now_ms: u64) -> bool {
+        match self.interval_ms {
+            Some(interval) if now_ms.saturating_sub(self.last_ms) >= interval => {
+                self.last_ms = now_ms;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+
+/// Result of feeding one byte of input to a `LineEditor`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edit {
+    /// The byte was appended to the line and should be echoed.
+    Echo(u8),
+    /// The last character was removed and should be erased from the terminal.
+    Erase,
+    /// A complete line was entered.
+    Line(Result<Command, ParseError>),
+}
+
+/// Accumulates console input into lines, handling backspace and overlong input.
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    line: heapless::Vec<u8, MAX_LINE_LENGTH>,
+    overflowed: bool,
+    /// The last line ended with a CR, so an LF straight after it belongs to the same line end.
+    after_cr: bool,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Processes one byte of input. Returns `None` if there is nothing to show for it.
+    pub fn push(&mut self, byte: u8) -> Option<Edit> {
+        if self.is_line_feed_after_cr(byte) {
+            return None;
+        }
+        match byte {
+            b'\r' | b'\n' => {
+                self.after_cr = byte == b'\r';
+                if self.line.is_empty() && !self.overflowed {
+                    return None;
+                }
+                let result = if self.overflowed {
+                    Err(ParseError::LineTooLong)
+                } else {
+                    core::str::from_utf8(&self.line)
+                        .map_err(|_| ParseError::UnknownCommand)
+                        .and_then(parse_command)
+                };
+                self.line.clear();
+                self.overflowed = false;
+                Some(Edit::Line(result))
+            }
+            BACKSPACE | DELETE => self.line.pop().map(|_| Edit::Erase),
+            _ => {
+                if self.line.push(byte).is_err() {
+                    self.overflowed = true;
+                    return None;
+                }
+                Some(Edit::Echo(byte))
+            }
+        }
+    }
+
+    /// Passes on a byte read without line editing, e.g. during a binary transfer that follows a
+    /// command. Returns `None` for the LF of a CR LF that ended the command, which isn't part of
+    /// the transfer. (So after a command ended by a bare CR, the transfer can't start with an LF.)
+    pub fn raw(&mut self, byte: u8) -> Option<u8> {
+        (!self.is_line_feed_after_cr(byte)).then_some(byte)
+    }
+
+    fn is_line_feed_after_cr(&mut self, byte: u8) -> bool {
+        core::mem::take(&mut self.after_cr) && byte == b'\n'
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use proptest::prelude::*;
+    #[cfg(not(feature = "kiosk"))]
+    use std::format;
+    use std::{string::String, vec::Vec};
+
+    fn feed(editor: &mut LineEditor, input: &[u8]) -> Vec<Edit> {
+        input.iter().filter_map(|&byte| editor.push(byte)).collect()
+    }
+
+    #[test]
+    fn parses_commands_case_insensitively() {
+        assert_eq!(parse_command("help"), Ok(Command::Help(None)));
+        assert_eq!(
+            parse_command("help battery"),
+            Ok(Command::Help(find_command("BATTERY")))
+        );
+        assert_eq!(parse_command("  Status  "), Ok(Command::Status));
+        assert_eq!(parse_command("temp"), Ok(Command::Temp));
+        assert_eq!(parse_command("battery"), Ok(Command::Battery));
+        #[cfg(not(feature = "kiosk"))]
+        assert_eq!(
+            parse_command("BATTERY warn 3350"),
+            Ok(Command::SetBattery(battery::Setting::Warn, 3350))
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "kiosk"))]
+    fn parses_settime() {
+        assert_eq!(
+            parse_command("settime 2024-02-29 23:59:58"),
+            Ok(Command::SetTime(DateTime {
+                year: 2024,
+                month: 2,
+                day: 29,
+                hours: 23,
+                minutes: 59,
+                seconds: 58,
+            }))
+        );
+        for bad in [
+            "SETTIME",
+            "SETTIME 2023-02-29 00:00:00",
+            "SETTIME 2024-01-01 00:00",
+            "SETTIME 2024-01-01 00:00:00:00",
+        ] {
+            assert_eq!(
+                parse_command(bad),
+                Err(ParseError::InvalidArguments),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "kiosk"))]
+    fn parses_pushfb() {
+        assert_eq!(
+            parse_command("PUSHFB 192000"),
+            Ok(Command::PushFb(
+                crate::image::FRAME_BYTES as u32,
+                Encoding::Raw
+            ))
+        );
+        assert_eq!(
+            parse_command("pushfb 2000 rle"),
+            Ok(Command::PushFb(2000, Encoding::Rle))
+        );
+        for bad in [
+            "PUSHFB",
+            "PUSHFB 1000",
+            "PUSHFB 192000 ZIP",
+            "PUSHFB 2001 RLE",
+            "PUSHFB -2 RLE",
+            "PUSHFB RLE 2000",
+        ] {
+            assert_eq!(
+                parse_command(bad),
+                Err(ParseError::InvalidArguments),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "kiosk"))]
+    fn pushfb_payload_follows_crlf() {
+        let payload = [0x11, 0x0A, 0x0D, 0x0A];
+        for line_end in [&b"\r\n"[..], b"\r", b"\n"] {
+            let mut input = b"PUSHFB 2000 RLE".to_vec();
+            input.extend_from_slice(line_end);
+            input.extend_from_slice(&payload);
+
+            let mut editor = LineEditor::new();
+            let mut bytes = input.iter();
+            let line = bytes.by_ref().find_map(|&byte| match editor.push(byte) {
+                Some(Edit::Line(line)) => Some(line),
+                _ => None,
+            });
+            assert_eq!(line, Some(Ok(Command::PushFb(2000, Encoding::Rle))));
+            let received: Vec<u8> = bytes.filter_map(|&byte| editor.raw(byte)).collect();
+            assert_eq!(received, payload);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "kiosk")]
+    fn kiosk_builds_leave_out_commands() {
+        for line in [
+            "SETTIME 2024-02-29 23:59:58",
+            "UPLOAD PHOTO1.BIN",
+            "PUSHFB 192000",
+            "SET WARN 3350",
+            "SLEEPUNTIL 2025-12-24T07:00",
+            "RESET",
+            "DFU",
+        ] {
+            assert_eq!(
+                parse_command(line),
+                Err(ParseError::UnknownCommand),
+                "{}",
+                line
+            );
+        }
+        assert_eq!(parse_command("HELP DFU"), Err(ParseError::InvalidArguments));
+
+        // These can still show the settings, but not change them.
+        assert_eq!(parse_command("BATTERY"), Ok(Command::Battery));
+        assert_eq!(parse_command("DITHER"), Ok(Command::Dither));
+        for line in ["BATTERY SHUTDOWN 4200", "DITHER NONE"] {
+            assert_eq!(
+                parse_command(line),
+                Err(ParseError::InvalidArguments),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn parses_term_settings() {
+        assert_eq!(parse_command("TERM"), Ok(Command::Term(None)));
+        assert_eq!(
+            parse_command("term echo off"),
+            Ok(Command::Term(Some((TermSetting::Echo, false))))
+        );
+        assert_eq!(
+            parse_command("TERM ANSI ON"),
+            Ok(Command::Term(Some((TermSetting::Ansi, true))))
+        );
+        assert_eq!(
+            parse_command("TERM ECHO"),
+            Err(ParseError::InvalidArguments)
+        );
+        assert_eq!(
+            parse_command("TERM ECHO 1"),
+            Err(ParseError::InvalidArguments)
+        );
+        assert_eq!(
+            parse_command("TERM COLOR ON"),
+            Err(ParseError::InvalidArguments)
+        );
+
+        let mut mode = TermMode::default();
+        mode.set(TermSetting::Crlf, false);
+        assert_eq!(
+            mode,
+            TermMode {
+                crlf: false,
+                ..TermMode::default()
+            }
+        );
+    }
+
+    #[test]
+    fn idle_monitor() {
+        assert_eq!(parse_command("MONITOR"), Ok(Command::Monitor));
+        assert_eq!(parse_command("monitor off"), Ok(Command::SetMonitor(None)));
+        assert_eq!(
+            parse_command("MONITOR 60"),
+            Ok(Command::SetMonitor(Some(60)))
+        );
+        assert_eq!(
+            parse_command("MONITOR 0"),
+            Err(ParseError::InvalidArguments)
+        );
+        assert_eq!(
+            parse_command("MONITOR 86401"),
+            Err(ParseError::InvalidArguments)
+        );
+
+        let mut monitor = IdleMonitor::new();
+        assert!(!monitor.poll(1_000_000));
+        monitor.set_interval(Some(10), 1_000);
+        assert_eq!(monitor.interval_seconds(), Some(10));
+        assert!(!monitor.poll(10_999));
+        assert!(monitor.poll(11_000));
+        assert!(!monitor.poll(11_001));
+        // A command resets the countdown.
+        monitor.activity(15_000);
+        assert!(!monitor.poll(21_000));
+        assert!(monitor.poll(25_000));
+        monitor.set_interval(None, 25_000);
+        assert!(!monitor.poll(1_000_000));
+    }
+
+    #[test]
+    fn parses_file_names() {
+        assert_eq!(parse_command("ls"), Ok(Command::Ls(None)));
+        let name = |command| match command {
+            Ok(Command::Ls(Some(name)) | Command::Cat(name)) => Some(name.as_str().into()),
+            #[cfg(not(feature = "kiosk"))]
+            Ok(Command::Upload(name)) => Some(name.as_str().into()),
+            _ => None::<String>,
+        };
+        assert_eq!(name(parse_command("LS IMAGES")), Some("IMAGES".into()));
+        assert_eq!(
+            name(parse_command("CAT CONFIG.TXT")),
+            Some("CONFIG.TXT".into())
+        );
+        assert_eq!(name(parse_command("cat readme")), Some("readme".into()));
+        #[cfg(not(feature = "kiosk"))]
+        assert_eq!(
+            name(parse_command("UPLOAD PHOTO_01.BMP")),
+            Some("PHOTO_01.BMP".into())
+        );
+
+        let path = FileName::parse("IMAGES/PHOTO1.BIN").unwrap();
+        assert_eq!(path.dir(), Some("IMAGES"));
+        assert_eq!(path.name(), "PHOTO1.BIN");
+        let path = FileName::parse("PHOTO1.BIN").unwrap();
+        assert_eq!(path.dir(), None);
+        assert_eq!(path.name(), "PHOTO1.BIN");
+
+        for bad in [
+            "CAT",
+            "CAT LONGFILENAME.TXT",
+            "CAT PHOTO.JPEG",
+            "CAT .TXT",
+            "CAT A.B.C",
+            "CAT A*.BMP",
+            "CAT /A.BMP",
+            "CAT IMAGES/",
+            "CAT A/B/C.BMP",
+            "LS A/B",
+        ] {
+            assert_eq!(
+                parse_command(bad),
+                Err(ParseError::InvalidArguments),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "kiosk"))]
+    fn parses_dither_modes() {
+        assert_eq!(parse_command("DITHER"), Ok(Command::Dither));
+        assert_eq!(
+            parse_command("dither atkinson"),
+            Ok(Command::SetDither(DitherMode::Atkinson))
+        );
+        for mode in DitherMode::ALL {
+            assert_eq!(
+                parse_command(&format!("DITHER {}", mode)),
+                Ok(Command::SetDither(mode))
+            );
+        }
+        assert_eq!(
+            parse_command("DITHER BAYER"),
+            Err(ParseError::InvalidArguments)
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "kiosk"))]
+    fn parses_settings() {
+        assert_eq!(
+            parse_command("set dither none"),
+            Ok(Command::Set(Setting::Dither(DitherMode::None)))
+        );
+        assert_eq!(
+            parse_command("SET WARN 3350"),
+            Ok(Command::Set(Setting::Battery(battery::Setting::Warn, 3350)))
+        );
+        assert_eq!(
+            parse_command("SET WAKE 07:30"),
+            Ok(Command::Set(Setting::Wake(Some(TimeOfDay {
+                hours: 7,
+                minutes: 30
+            }))))
+        );
+        assert_eq!(
+            parse_command("SET WAKE off"),
+            Ok(Command::Set(Setting::Wake(None)))
+        );
+        assert_eq!(
+            parse_command("SET MODE art"),
+            Ok(Command::Set(Setting::Mode(Some(DisplayMode::Art))))
+        );
+        assert_eq!(
+            parse_command("SET MODE AUTO"),
+            Ok(Command::Set(Setting::Mode(None)))
+        );
+        assert_eq!(
+            parse_command("SET ROTATION 180"),
+            Ok(Command::Set(Setting::Rotation(Rotation::Half)))
+        );
+        assert_eq!(
+            parse_command("SET QUOTES 2"),
+            Ok(Command::Set(Setting::Quotes(2)))
+        );
+        for bad in [
+            "SET DITHER",
+            "SET DITHER BAYER",
+            "SET WARN low",
+            "SET WAKE 7:30",
+            "SET WAKE 24:00",
+            "SET MODE photos",
+            "SET ROTATION 45",
+            "SET QUOTES 256",
+            "SET BRIGHTNESS 50",
+        ] {
+            assert_eq!(
+                parse_command(bad),
+                Err(ParseError::InvalidArguments),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn parses_setting_lookups() {
+        assert_eq!(
+            parse_command("GET hysteresis"),
+            Ok(Command::Get(config::Key::Battery(
+                crate::battery::Setting::Hysteresis
+            )))
+        );
+        assert_eq!(parse_command("SHOWCFG"), Ok(Command::ShowConfig));
+        assert_eq!(
+            parse_command("GET rotation"),
+            Ok(Command::Get(config::Key::Rotation))
+        );
+        for bad in ["GET", "GET BRIGHTNESS"] {
+            assert_eq!(
+                parse_command(bad),
+                Err(ParseError::InvalidArguments),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        let wake = DateTime {
+            year: 2025,
+            month: 12,
+            day: 24,
+            hours: 7,
+            minutes: 0,
+            seconds: 0,
+        };
+        assert_eq!(parse_command("PLAN"), Ok(Command::Plan(None)));
+        assert_eq!(
+            parse_command("PLAN 2025-12-24T07:00"),
+            Ok(Command::Plan(Some(wake)))
+        );
+        assert_eq!(
+            parse_command("plan 2025-12-24t07:00:30"),
+            Ok(Command::Plan(Some(DateTime {
+                seconds: 30,
+                ..wake
+            })))
+        );
+        for bad in [
+            "PLAN 07:00",
+            "PLAN 2025-12-24",
+            "PLAN 2025-12-24 07:00",
+            "PLAN 2025-12-24T07",
+            "PLAN 2025-12-24T24:00",
+            "PLAN 2025-12-24T07:00:00:00",
+        ] {
+            assert_eq!(
+                parse_command(bad),
+                Err(ParseError::InvalidArguments),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "kiosk"))]
+    fn parses_sleepuntil() {
+        let wake = DateTime {
+            year: 2025,
+            month: 12,
+            day: 24,
+            hours: 7,
+            minutes: 0,
+            seconds: 0,
+        };
+        assert_eq!(
+            parse_command("SLEEPUNTIL 2025-12-24T07:00"),
+            Ok(Command::SleepUntil(wake))
+        );
+        assert_eq!(
+            parse_command("sleepuntil 2025-12-24t07:00:30"),
+            Ok(Command::SleepUntil(DateTime {
+                seconds: 30,
+                ..wake
+            }))
+        );
+        for bad in [
+            "SLEEPUNTIL",
+            "SLEEPUNTIL 2025-12-24",
+            "SLEEPUNTIL 2025-12-24 07:00",
+            "SLEEPUNTIL 2025-12-24T07",
+            "SLEEPUNTIL 2025-12-24T24:00",
+            "SLEEPUNTIL 2025-12-24T07:00:00:00",
+        ] {
+            assert_eq!(
+                parse_command(bad),
+                Err(ParseError::InvalidArguments),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert_eq!(parse_command(""), Err(ParseError::UnknownCommand));
+        assert_eq!(parse_command("FOO"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse_command("HELP me"), Err(ParseError::InvalidArguments));
+        assert_eq!(
+            parse_command("HELP TIME now"),
+            Err(ParseError::InvalidArguments)
+        );
+        assert_eq!(
+            parse_command("BATTERY SHUTDOWN"),
+            Err(ParseError::InvalidArguments)
+        );
+        assert_eq!(
+            parse_command("BATTERY LOW 3000"),
+            Err(ParseError::InvalidArguments)
+        );
+        assert_eq!(
+            parse_command("BATTERY WARN -1"),
+            Err(ParseError::InvalidArguments)
+        );
+    }
+
+    #[test]
+    fn edits_lines() {
+        let mut editor = LineEditor::new();
+        assert_eq!(feed(&mut editor, b"\r\n"), []);
+        assert_eq!(
+            feed(&mut editor, b"hx\x08elp\r"),
+            [
+                Edit::Echo(b'h'),
+                Edit::Echo(b'x'),
+                Edit::Erase,
+                Edit::Echo(b'e'),
+                Edit::Echo(b'l'),
+                Edit::Echo(b'p'),
+                Edit::Line(Ok(Command::Help(None))),
+            ]
+        );
+        // Backspace on an empty line does nothing.
+        assert_eq!(feed(&mut editor, b"\x7f"), []);
+    }
+
+    #[test]
+    fn reports_overlong_lines() {
+        let mut editor = LineEditor::new();
+        let long = [b'a'; MAX_LINE_LENGTH + 10];
+        let edits = feed(&mut editor, &long);
+        assert_eq!(edits.len(), MAX_LINE_LENGTH);
+        assert_eq!(
+            editor.push(b'\n'),
+            Some(Edit::Line(Err(ParseError::LineTooLong)))
+        );
+        // The editor recovers for the next line.
+        assert_eq!(
+            feed(&mut editor, b"HELP\n").last(),
+            Some(&Edit::Line(Ok(Command::Help(None))))
+        );
+    }
+
+    const TERMINAL_WIDTH: usize = 80;
+
+    #[test]
+    fn help_pages() {
+        let names = |commands: &[CommandSpec]| -> Vec<&str> {
+            commands.iter().map(|command| command.name).collect()
+        };
+        // More than a page, even in kiosk builds.
+        assert!(COMMANDS.len() >= PAGE_LINES);
+        let mut pager = HelpPager::new();
+        let first = pager.start(COMMANDS);
+        assert_eq!(first.len(), PAGE_LINES - 1);
+        assert!(pager.is_paused());
+        // The LF after the CR that ended the HELP line.
+        assert_eq!(pager.key(b'\n'), None);
+
+        let mut listed = names(first);
+        while pager.is_paused() {
+            let page = pager.key(b' ').unwrap();
+            assert!(!page.is_empty() && page.len() <= PAGE_LINES);
+            listed.extend(names(page));
+        }
+        assert_eq!(listed, names(COMMANDS));
+        assert_eq!(pager.key(b' '), Some(&[][..]));
+
+        pager.start(COMMANDS);
+        assert_eq!(pager.key(b'Q'), Some(&[][..]));
+        assert!(!pager.is_paused());
+    }
+
+    #[test]
+    fn help_fits_the_terminal() {
+        for command in COMMANDS {
+            assert_eq!(find_command(command.name), Some(command));
+            assert!(command.usage.len() < USAGE_WIDTH, "{}", command.name);
+            assert!(
+                2 + USAGE_WIDTH + command.summary.len() <= TERMINAL_WIDTH,
+                "{}",
+                command.name
+            );
+            for line in command.details.lines() {
+                assert!(line.len() <= TERMINAL_WIDTH, "{}", command.name);
+            }
+        }
+    }
+
+    #[test]
+    fn argument_counts_are_checked() {
+        for command in COMMANDS {
+            assert!(command.max_args <= MAX_ARGS, "{}", command.name);
+            let mut line = String::from(command.name);
+            for count in 0..=MAX_ARGS + 1 {
+                if count < command.min_args || count > command.max_args {
+                    assert_eq!(
+                        parse_command(&line),
+                        Err(ParseError::InvalidArguments),
+                        "{}",
+                        line
+                    );
+                }
+                line.push_str(" x");
+            }
+        }
+    }
+
+    #[cfg(not(feature = "kiosk"))]
+    fn valid_datetime() -> impl Strategy<Value = DateTime> {
+        (
+            2000u16..=2099,
+            1u8..=12,
+            1u8..=31,
+            0u8..24,
+            0u8..60,
+            0u8..60,
+        )
+            .prop_map(|(year, month, day, hours, minutes, seconds)| DateTime {
+                year,
+                month,
+                day: day.min(crate::datetime::days_in_month(year, month)),
+                hours,
+                minutes,
+                seconds,
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn parse_never_panics(line in any::<String>()) {
+            let _ = parse_command(&line);
+        }
+
+        #[test]
+        #[cfg(not(feature = "kiosk"))]
+        fn parsed_times_are_valid(line in "SETTIME [0-9]{1,5}-[0-9]{1,3}-[0-9]{1,3} [0-9]{1,3}:[0-9]{1,3}:[0-9]{1,3}") {
+            if let Ok(Command::SetTime(datetime)) = parse_command(&line) {
+                prop_assert!(datetime.is_valid());
+            }
+        }
+
+        #[test]
+        #[cfg(not(feature = "kiosk"))]
+        fn settime_round_trips(datetime in valid_datetime()) {
+            prop_assert_eq!(
+                parse_command(&format!("SETTIME {}", datetime)),
+                Ok(Command::SetTime(datetime))
+            );
+        }
+
+        #[test]
+        fn editor_never_panics(input in proptest::collection::vec(any::<u8>(), 0..512)) {
+            let mut editor = LineEditor::new();
+            for byte in input {
+                #[cfg_attr(feature = "kiosk", allow(unused_variables))]
+                let edit = editor.push(byte);
+                #[cfg(not(feature = "kiosk"))]
+                if let Some(Edit::Line(Ok(Command::SetTime(datetime)))) = edit {
+                    prop_assert!(datetime.is_valid());
+                }
+                prop_assert!(editor.line.len() <= MAX_LINE_LENGTH);
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+// Calendar arithmetic shared by the RTC driver and the console.
+
+pub const SECONDS_PER_DAY: u32 = 24 * 60 * 60;
+
+/// Calendar date and time, as kept by the RTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DateTime {
+    /// Full year; the RTC can only represent 2000 through 2099.
+    pub year: u16,
+    /// Month of the year (1-12).
+    pub month: u8,
+    /// Day of the month (1-31).
+    pub day: u8,
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+}
+
+impl DateTime {
+    /// Returns true if every component is in range for the RTC.
+    pub fn is_valid(&self) -> bool {
+        (2000..=2099).contains(&self.year)
+            && (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hours < 24
+            && self.minutes < 60
+            && self.seconds < 60
+    }
+
+    /// Day of the week, with Sunday as 0.
+    pub fn weekday(&self) -> u8 {
+        // Sakamoto's method.
+        const MONTH_OFFSETS: [u16; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+        let year = if self.month < 3 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        ((year + year / 4 - year / 100
+            + year / 400
+            + MONTH_OFFSETS[self.month as usize - 1]
+            + self.day as u16)
+            % 7) as u8
+    }
+
+    /// Seconds since 2000-01-01 00:00:00.
+    pub fn to_timestamp(&self) -> u32 {
+        let mut days = (2000..self.year)
+            .map(|year| if is_leap_year(year) { 366 } else { 365 })
+            .sum::<u32>();
+        days += (1..self.month)
+            .map(|month| days_in_month(self.year, month) as u32)
+            .sum::<u32>();
+        days += self.day as u32 - 1;
+        days * SECONDS_PER_DAY
+            + self.hours as u32 * 3600
+            + self.minutes as u32 * 60
+            + self.seconds as u32
+    }
+
+    /// Inverse of `to_timestamp`.
+    pub fn from_timestamp(timestamp: u32) -> DateTime {
+        let mut days = timestamp / SECONDS_PER_DAY;
+        let seconds_of_day = timestamp % SECONDS_PER_DAY;
+        let mut year = 2000;
+        loop {
+            let days_in_year = if is_leap_year(year) { 366 } else { 365 };
+            if days < days_in_year {
+                break;
+            }
+            days -= days_in_year;
+            year += 1;
+        }
+        let mut month = 1;
+        while days >= days_in_month(year, month) as u32 {
+            days -= days_in_month(year, month) as u32;
+            month += 1;
+        }
+        DateTime {
+            year,
+            month,
+            day: days as u8 + 1,
+            hours: (seconds_of_day / 3600) as u8,
+            minutes: (seconds_of_day / 60 % 60) as u8,
+            seconds: (seconds_of_day % 60) as u8,
+        }
+    }
+
+    /// Returns the time `seconds` from now, or `None` if that is beyond what the RTC can hold.
+    pub fn checked_add_seconds(&self, seconds: u32) -> Option<DateTime> {
+        let later = DateTime::from_timestamp(self.to_timestamp().checked_add(seconds)?);
+        later.is_valid().then_some(later)
+    }
+
+    /// Returns the start of the next day, or `None` if that is beyond what the RTC can hold.
+    pub fn next_midnight(&self) -> Option<DateTime> {
+        DateTime {
+            hours: 0,
+            minutes: 0,
+            seconds: 0,
+            ..*self
+        }
+        .checked_add_seconds(SECONDS_PER_DAY)
+    }
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hours, self.minutes, self.seconds
+        )
+    }
+}
+
+pub fn is_leap_year(year: u16) -> bool {
+    (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400)
+}
+
+pub fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::format;
+
+    fn date(year: u16, month: u8, day: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hours: 0,
+            minutes: 0,
+            seconds: 0,
+        }
+    }
+
+    #[test]
+    fn leap_years() {
+        assert!(is_leap_year(2024));
+        assert!(!is_leap_year(2023));
+        assert!(!is_leap_year(2100));
+        assert!(is_leap_year(2000));
+    }
+
+    #[test]
+    fn month_lengths() {
+        assert_eq!(days_in_month(2024, 2), 29);
+        assert_eq!(days_in_month(2023, 2), 28);
+        assert_eq!(days_in_month(2023, 4), 30);
+        assert_eq!(days_in_month(2023, 12), 31);
+    }
+
+    #[test]
+    fn weekdays() {
+        assert_eq!(date(2000, 1, 1).weekday(), 6);
+        assert_eq!(date(2024, 2, 29).weekday(), 4);
+        assert_eq!(date(2024, 3, 1).weekday(), 5);
+        assert_eq!(date(2024, 12, 25).weekday(), 3);
+        assert_eq!(date(2099, 12, 31).weekday(), 4);
+    }
+
+    #[test]
+    fn validation() {
+        assert!(date(2024, 2, 29).is_valid());
+        assert!(!date(2023, 2, 29).is_valid());
+        assert!(!date(1999, 12, 31).is_valid());
+        assert!(!date(2100, 1, 1).is_valid());
+        assert!(!date(2024, 0, 1).is_valid());
+        assert!(!date(2024, 13, 1).is_valid());
+        assert!(!date(2024, 1, 0).is_valid());
+        assert!(!DateTime {
+            hours: 24,
+            ..date(2024, 1, 1)
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(date(2000, 1, 1).to_timestamp(), 0);
+        assert_eq!(date(2000, 3, 1).to_timestamp(), 60 * SECONDS_PER_DAY);
+        assert_eq!(date(2001, 1, 1).to_timestamp(), 366 * SECONDS_PER_DAY);
+        for datetime in [
+            date(2000, 1, 1),
+            date(2024, 2, 29),
+            date(2099, 12, 31),
+            DateTime {
+                hours: 23,
+                minutes: 59,
+                seconds: 59,
+                ..date(2023, 12, 31)
+            },
+        ] {
+            assert_eq!(DateTime::from_timestamp(datetime.to_timestamp()), datetime);
+        }
+    }
+
+    #[test]
+    fn adding_seconds() {
+        let end_of_year = DateTime {
+            hours: 23,
+            minutes: 30,
+            ..date(2023, 12, 31)
+        };
+        assert_eq!(
+            end_of_year.checked_add_seconds(3600),
+            Some(DateTime {
+                minutes: 30,
+                ..date(2024, 1, 1)
+            })
+        );
+        assert_eq!(
+            date(2024, 2, 28).checked_add_seconds(SECONDS_PER_DAY),
+            Some(date(2024, 2, 29))
+        );
+        assert_eq!(
+            date(2023, 2, 28).checked_add_seconds(SECONDS_PER_DAY),
+            Some(date(2023, 3, 1))
+        );
+        assert_eq!(
+            date(2099, 12, 31).checked_add_seconds(SECONDS_PER_DAY),
+            None
+        );
+    }
+
+    #[test]
+    fn midnight() {
+        let evening = DateTime {
+            hours: 21,
+            minutes: 15,
+            seconds: 3,
+            ..date(2024, 2, 28)
+        };
+        assert_eq!(evening.next_midnight(), Some(date(2024, 2, 29)));
+        assert_eq!(date(2024, 12, 31).next_midnight(), Some(date(2025, 1, 1)));
+        assert_eq!(date(2099, 12, 31).next_midnight(), None);
+    }
+
+    #[test]
+    fn display() {
+        let datetime = DateTime {
+            hours: 7,
+            minutes: 5,
+            seconds: 9,
+            ..date(2024, 6, 30)
+        };
+        assert_eq!(format!("{}", datetime), "2024-06-30 07:05:09");
+    }
+}
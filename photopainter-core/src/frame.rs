@@ -0,0 +1,213 @@
+// Receiving ready-made frame buffers from a host.
+//
+// PUSHFB lets a script on another machine render whatever it likes and send the result straight
+// to the panel, with no decoding on the frame. The data is the panel's own frame buffer format
+// (two 4-bit colors per byte, row by row from the top left), either as it is or run-length
+// encoded. Like BMP decoding, it is handed on a row at a time, since the panel is fed that way
+// and a whole frame would take most of the RAM.
+//
+// The run-length encoding is pairs of bytes: a count from 1 to 255, then the byte to repeat that
+// many times. Large areas of one color, which is what rendered text and charts mostly are, shrink
+// to almost nothing.
+
+use core::fmt;
+
+use crate::image::{FRAME_BYTES, FRAME_ROW_BYTES, HEIGHT};
+
+/// How the frame buffer is sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Encoding {
+    /// Exactly `FRAME_BYTES` bytes, as they are.
+    Raw,
+    /// Pairs of a repeat count and a byte.
+    Rle,
+}
+
+impl Encoding {
+    /// Returns true if `len` bytes could hold a whole frame in this encoding.
+    pub fn is_valid_length(self, len: u32) -> bool {
+        let len = len as usize;
+        match self {
+            Encoding::Raw => len == FRAME_BYTES,
+            // At best, every pair is a run of 255; at worst, of 1.
+            Encoding::Rle => {
+                len.is_multiple_of(2)
+                    && len >= 2 * FRAME_BYTES.div_ceil(255)
+                    && len <= 2 * FRAME_BYTES
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FrameError {
+    /// More data than fits in a frame.
+    TooLong,
+    /// The data ended before the frame was complete.
+    TooShort,
+    /// A run-length pair with a count of zero.
+    EmptyRun,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLong => f.write_str("more data than fits in a frame"),
+            FrameError::TooShort => f.write_str("data ended before the frame was complete"),
+            FrameError::EmptyRun => f.write_str("run length of zero"),
+        }
+    }
+}
+
+/// Rebuilds a frame buffer from received bytes, a row at a time.
+#[derive(Debug)]
+pub struct FrameReceiver {
+    encoding: Encoding,
+    row: [u8; FRAME_ROW_BYTES],
+    /// Bytes filled in `row`.
+    len: usize,
+    /// Rows completed so far.
+    rows: usize,
+    /// The count of a run-length pair whose byte hasn't arrived yet.
+    count: Option<u8>,
+}
+
+impl FrameReceiver {
+    pub fn new(encoding: Encoding) -> Self {
+        FrameReceiver {
+            encoding,
+            row: [0; FRAME_ROW_BYTES],
+            len: 0,
+            rows: 0,
+            count: None,
+        }
+    }
+
+    /// Processes one received byte, calling `row_done` for each row it completes.
+    pub fn push(
+        &mut self,
+        byte: u8,
+        mut row_done: impl FnMut(&[u8; FRAME_ROW_BYTES]),
+    ) -> Result<(), FrameError> {
+        let (count, value) = match (self.encoding, self.count.take()) {
+            (Encoding::Raw, _) => (1, byte),
+            (Encoding::Rle, None) if byte == 0 => return Err(FrameError::EmptyRun),
+            (Encoding::Rle, None) => {
+                self.count = Some(byte);
+                return Ok(());
+            }
+            (Encoding::Rle, Some(count)) => (count, byte),
+        };
+        for _ in 0..count {
+            if self.rows == HEIGHT {
+                return Err(FrameError::TooLong);
+            }
+            self.row[self.len] = value;
+            self.len += 1;
+            if self.len == FRAME_ROW_BYTES {
+                row_done(&self.row);
+                self.len = 0;
+                self.rows += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that a whole frame has been received.
+    pub fn finish(&self) -> Result<(), FrameError> {
+        if self.rows == HEIGHT && self.count.is_none() {
+            Ok(())
+        } else {
+            Err(FrameError::TooShort)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    /// Feeds in `input`, and returns the rows it produced.
+    fn receive(
+        receiver: &mut FrameReceiver,
+        input: &[u8],
+    ) -> Result<Vec<[u8; FRAME_ROW_BYTES]>, FrameError> {
+        let mut rows = Vec::new();
+        for &byte in input {
+            receiver.push(byte, |row| rows.push(*row))?;
+        }
+        Ok(rows)
+    }
+
+    #[test]
+    fn raw_frames() {
+        let frame: Vec<u8> = (0..FRAME_BYTES).map(|i| (i % 7) as u8).collect();
+        let mut receiver = FrameReceiver::new(Encoding::Raw);
+        let rows = receive(&mut receiver, &frame).unwrap();
+        assert_eq!(rows.len(), HEIGHT);
+        assert_eq!(rows.concat(), frame);
+        assert_eq!(receiver.finish(), Ok(()));
+        assert_eq!(receiver.push(0, |_| {}), Err(FrameError::TooLong));
+
+        let mut receiver = FrameReceiver::new(Encoding::Raw);
+        receive(&mut receiver, &frame[1..]).unwrap();
+        assert_eq!(receiver.finish(), Err(FrameError::TooShort));
+    }
+
+    #[test]
+    fn rle_frames() {
+        // The top half white, the bottom half alternating black and white pixels.
+        let mut input = Vec::new();
+        for _ in 0..FRAME_BYTES / 2 / 250 {
+            input.extend([250, 0x11]);
+        }
+        for _ in 0..FRAME_BYTES / 2 {
+            input.extend([1, 0x01]);
+        }
+        assert!(Encoding::Rle.is_valid_length(input.len() as u32));
+
+        let mut receiver = FrameReceiver::new(Encoding::Rle);
+        let rows = receive(&mut receiver, &input).unwrap();
+        assert_eq!(rows.len(), HEIGHT);
+        assert!(rows[..HEIGHT / 2].iter().flatten().all(|&b| b == 0x11));
+        assert!(rows[HEIGHT / 2..].iter().flatten().all(|&b| b == 0x01));
+        assert_eq!(receiver.finish(), Ok(()));
+        assert_eq!(receiver.push(1, |_| {}), Ok(()));
+        assert_eq!(receiver.push(0x11, |_| {}), Err(FrameError::TooLong));
+    }
+
+    #[test]
+    fn bad_rle() {
+        let mut receiver = FrameReceiver::new(Encoding::Rle);
+        assert_eq!(
+            receive(&mut receiver, &[2, 0x11, 0]),
+            Err(FrameError::EmptyRun)
+        );
+
+        // A count without its byte.
+        let mut input = Vec::new();
+        for _ in 0..FRAME_BYTES / 200 - 1 {
+            input.extend([200, 0x11]);
+        }
+        input.push(1);
+        let mut receiver = FrameReceiver::new(Encoding::Rle);
+        receive(&mut receiver, &input).unwrap();
+        assert_eq!(receiver.finish(), Err(FrameError::TooShort));
+    }
+
+    #[test]
+    fn lengths() {
+        assert!(Encoding::Raw.is_valid_length(FRAME_BYTES as u32));
+        assert!(!Encoding::Raw.is_valid_length(FRAME_BYTES as u32 - 1));
+        assert!(Encoding::Rle.is_valid_length(2 * FRAME_BYTES as u32));
+        assert!(!Encoding::Rle.is_valid_length(2 * FRAME_BYTES as u32 + 2));
+        assert!(!Encoding::Rle.is_valid_length(1001));
+        assert!(!Encoding::Rle.is_valid_length(2));
+        assert!(!Encoding::Rle.is_valid_length(0));
+    }
+}
@@ -0,0 +1,593 @@
+// Turning 24-bit BMP files into frames for the 7-color panel.
+//
+// The panel can only show seven colors, so photos are dithered: by default with Floyd–Steinberg
+// error diffusion, but `DitherMode` has alternatives that suit some pictures better. Decoding is
+// done a row at a time: a whole frame of RGB pixels wouldn't fit in RAM, and the panel is fed its
+// frame buffer row by row anyway. The caller reads each row from the file at the offset
+// `Bmp::row_span` gives, and `Ditherer` packs it into panel pixels.
+//
+// Images that aren't 800x480 are centered, cropping whatever doesn't fit and leaving white
+// around anything smaller.
+
+use core::fmt;
+
+/// Size of the panel in pixels.
+pub const WIDTH: usize = 800;
+pub const HEIGHT: usize = 480;
+
+/// Bytes per row of a frame buffer: two 4-bit pixels per byte.
+pub const FRAME_ROW_BYTES: usize = WIDTH / 2;
+
+/// Size of a whole frame buffer.
+pub const FRAME_BYTES: usize = FRAME_ROW_BYTES * HEIGHT;
+
+/// Largest BMP width or height accepted. Anything bigger would mostly be cropped away, and this
+/// keeps the file offsets well inside a `u32`.
+pub const MAX_BMP_SIZE: u32 = 8192;
+
+/// Bytes needed to parse a BMP header: the file header plus a BITMAPINFOHEADER.
+pub const BMP_HEADER_LENGTH: usize = 54;
+
+/// The colors the panel can show, with their values in the frame buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Color {
+    Black = 0,
+    White = 1,
+    Green = 2,
+    Blue = 3,
+    Red = 4,
+    Yellow = 5,
+    Orange = 6,
+}
+
+/// Roughly what each panel color looks like, in RGB.
+const PALETTE: [(Color, [i16; 3]); 7] = [
+    (Color::Black, [0, 0, 0]),
+    (Color::White, [255, 255, 255]),
+    (Color::Green, [0, 255, 0]),
+    (Color::Blue, [0, 0, 255]),
+    (Color::Red, [255, 0, 0]),
+    (Color::Yellow, [255, 255, 0]),
+    (Color::Orange, [255, 128, 0]),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BmpError {
+    /// The file doesn't start with a BMP header.
+    NotBmp,
+    /// A BMP, but not an uncompressed 24-bit one.
+    Unsupported,
+    /// Wider or taller than `MAX_BMP_SIZE`.
+    TooLarge,
+    /// The file ends before the last of the pixels.
+    Truncated,
+}
+
+impl fmt::Display for BmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BmpError::NotBmp => f.write_str("not a BMP file"),
+            BmpError::Unsupported => {
+                f.write_str("only uncompressed 24-bit BMP files are supported")
+            }
+            BmpError::TooLarge => write!(
+                f,
+                "images can be at most {} pixels wide or tall",
+                MAX_BMP_SIZE
+            ),
+            BmpError::Truncated => f.write_str("the file is cut short"),
+        }
+    }
+}
+
+/// The layout of a BMP file's pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Bmp {
+    pub width: u32,
+    pub height: u32,
+    /// Most BMP files store the bottom row first.
+    top_down: bool,
+    data_offset: u32,
+}
+
+/// Where to find the pixels for one row of the panel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RowSpan {
+    /// Offset of the first pixel in the file.
+    pub offset: u32,
+    /// Number of pixels.
+    pub pixels: usize,
+    /// The panel column the first pixel goes in.
+    pub x: usize,
+}
+
+impl Bmp {
+    /// Parses the first `BMP_HEADER_LENGTH` bytes of a file.
+    pub fn parse(header: &[u8]) -> Result<Bmp, BmpError> {
+        if header.len() < BMP_HEADER_LENGTH || !header.starts_with(b"BM") {
+            return Err(BmpError::NotBmp);
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]);
+        let u32_at =
+            |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+        let data_offset = u32_at(10);
+        let info_length = u32_at(14);
+        let width = u32_at(18) as i32;
+        let height = u32_at(22) as i32;
+        let bits_per_pixel = u16_at(28);
+        let compression = u32_at(30);
+        // Later header versions only add fields after these.
+        if info_length < 40 || width <= 0 || height == 0 || height == i32::MIN {
+            return Err(BmpError::NotBmp);
+        }
+        if bits_per_pixel != 24 || compression != 0 {
+            return Err(BmpError::Unsupported);
+        }
+        let bmp = Bmp {
+            width: width as u32,
+            height: height.unsigned_abs(),
+            top_down: height < 0,
+            data_offset,
+        };
+        if bmp.width > MAX_BMP_SIZE || bmp.height > MAX_BMP_SIZE {
+            return Err(BmpError::TooLarge);
+        }
+        // With the size limited, only the data offset can push the end of the pixels past 4 GB,
+        // which no file on a FAT32 card reaches.
+        if data_offset.checked_add(bmp.stride() * bmp.height).is_none() {
+            return Err(BmpError::NotBmp);
+        }
+        Ok(bmp)
+    }
+
+    /// Checks that a file of `length` bytes holds all of the pixels. Rows are only read where
+    /// `row_span` says, so a short file would otherwise only show up as a failed read.
+    pub fn check_length(&self, length: u32) -> Result<(), BmpError> {
+        // The padding after the last row isn't read, and some programs leave it out.
+        let end = self.data_offset + (self.height - 1) * self.stride() + self.width * 3;
+        if length < end {
+            return Err(BmpError::Truncated);
+        }
+        Ok(())
+    }
+
+    /// Bytes per row in the file, which are padded to a multiple of four.
+    fn stride(&self) -> u32 {
+        (self.width * 3 + 3) & !3
+    }
+
+    /// Where the pixels for panel row `y` are, or `None` if the row is outside the image.
+    pub fn row_span(&self, y: usize) -> Option<RowSpan> {
+        let (first_row, row_offset) = centered(self.height, HEIGHT);
+        let row = (y + first_row).checked_sub(row_offset)?;
+        if row >= self.height as usize {
+            return None;
+        }
+        let stored_row = if self.top_down {
+            row
+        } else {
+            self.height as usize - 1 - row
+        };
+        let (first_column, x) = centered(self.width, WIDTH);
+        Some(RowSpan {
+            offset: self.data_offset + stored_row as u32 * self.stride() + first_column as u32 * 3,
+            pixels: (self.width as usize - first_column).min(WIDTH - x),
+            x,
+        })
+    }
+}
+
+/// Centers an image dimension of `size` on a panel dimension of `panel`. Returns how much of the
+/// image is cropped off the start, and how far in from the panel edge it starts.
+fn centered(size: u32, panel: usize) -> (usize, usize) {
+    let size = size as usize;
+    if size > panel {
+        ((size - panel) / 2, 0)
+    } else {
+        (0, (panel - size) / 2)
+    }
+}
+
+/// The panel color closest to an RGB value.
+fn nearest(rgb: [i16; 3]) -> (Color, [i16; 3]) {
+    let distance = |color: &[i16; 3]| -> i32 {
+        (0..3)
+            .map(|i| {
+                let d = (rgb[i] - color[i]) as i32;
+                d * d
+            })
+            .sum()
+    };
+    PALETTE
+        .iter()
+        .copied()
+        .min_by_key(|(_, color)| distance(color))
+        .unwrap_or(PALETTE[0])
+}
+
+/// How to approximate colors the panel can't show.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DitherMode {
+    /// Use the nearest color. Best for drawings with flat colors.
+    None,
+    /// Add a fixed 4x4 pattern, which looks like printed halftone.
+    Ordered4x4,
+    /// Spread each pixel's error over its neighbours. Usually the best for photos.
+    #[default]
+    FloydSteinberg,
+    /// Like Floyd–Steinberg, but dropping a quarter of the error, for more contrast.
+    Atkinson,
+}
+
+impl DitherMode {
+    pub const ALL: [DitherMode; 4] = [
+        DitherMode::None,
+        DitherMode::Ordered4x4,
+        DitherMode::FloydSteinberg,
+        DitherMode::Atkinson,
+    ];
+
+    /// The name used on the console.
+    pub fn name(&self) -> &'static str {
+        match self {
+            DitherMode::None => "NONE",
+            DitherMode::Ordered4x4 => "ORDERED",
+            DitherMode::FloydSteinberg => "FLOYD",
+            DitherMode::Atkinson => "ATKINSON",
+        }
+    }
+
+    /// Turns the mode into a byte, for the settings record in flash.
+    pub fn to_bits(self) -> u8 {
+        self as u8
+    }
+
+    /// Inverse of `to_bits`, or `None` if `bits` isn't a mode.
+    pub fn from_bits(bits: u8) -> Option<DitherMode> {
+        DitherMode::ALL.get(bits as usize).copied()
+    }
+}
+
+impl fmt::Display for DitherMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How far pictures are turned clockwise, for frames that don't hang the way up the panel
+/// expects.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Rotation {
+    #[default]
+    None,
+    Quarter,
+    Half,
+    ThreeQuarters,
+}
+
+impl Rotation {
+    pub const ALL: [Rotation; 4] = [
+        Rotation::None,
+        Rotation::Quarter,
+        Rotation::Half,
+        Rotation::ThreeQuarters,
+    ];
+
+    pub fn degrees(self) -> u16 {
+        self as u16 * 90
+    }
+
+    /// Looks up a rotation by its angle in degrees.
+    pub fn from_degrees(degrees: u16) -> Option<Rotation> {
+        Rotation::ALL
+            .into_iter()
+            .find(|rotation| rotation.degrees() == degrees)
+    }
+
+    /// Turns the rotation into a byte, for the settings record in flash.
+    pub fn to_bits(self) -> u8 {
+        self as u8
+    }
+
+    /// Inverse of `to_bits`, or `None` if `bits` isn't a rotation.
+    pub fn from_bits(bits: u8) -> Option<Rotation> {
+        Rotation::ALL.get(bits as usize).copied()
+    }
+}
+
+impl fmt::Display for Rotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.degrees())
+    }
+}
+
+/// Thresholds for ordered dithering, in the order that spreads them out best.
+const BAYER_4X4: [[i16; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Spare columns on either side of the error rows, so that the edges need no special cases.
+const MARGIN: usize = 2;
+
+/// Dithers an image one row at a time, from the top.
+pub struct Ditherer {
+    mode: DitherMode,
+    row: usize,
+    /// Error carried into the coming rows, in sixteenths. Atkinson reaches two rows ahead, so
+    /// three rows are kept and used in rotation.
+    errors: [[[i16; 3]; WIDTH + 2 * MARGIN]; 3],
+}
+
+impl Ditherer {
+    pub fn new(mode: DitherMode) -> Self {
+        Ditherer {
+            mode,
+            row: 0,
+            errors: [[[0; 3]; WIDTH + 2 * MARGIN]; 3],
+        }
+    }
+
+    /// Dithers a row of `WIDTH` pixels, stored as BMP does (blue, green, red), into the panel's
+    /// frame buffer format.
+    pub fn dither_row(&mut self, bgr: &[u8], out: &mut [u8; FRAME_ROW_BYTES]) {
+        let y = self.row;
+        self.row += 1;
+        let (current, next, after) = (y % 3, (y + 1) % 3, (y + 2) % 3);
+        let errors = &mut self.errors;
+        for (x, pixel) in bgr.chunks_exact(3).take(WIDTH).enumerate() {
+            let bias = match self.mode {
+                DitherMode::Ordered4x4 => (BAYER_4X4[y % 4][x % 4] * 2 - 15) * 8,
+                _ => 0,
+            };
+            let e = x + MARGIN;
+            let mut rgb = [pixel[2], pixel[1], pixel[0]].map(i16::from);
+            for (value, error) in rgb.iter_mut().zip(errors[current][e]) {
+                *value = (*value + bias + error / 16).clamp(0, 255);
+            }
+            let (color, shown) = nearest(rgb);
+            for i in 0..3 {
+                let error = rgb[i] - shown[i];
+                match self.mode {
+                    DitherMode::None | DitherMode::Ordered4x4 => {}
+                    DitherMode::FloydSteinberg => {
+                        errors[current][e + 1][i] += error * 7;
+                        errors[next][e - 1][i] += error * 3;
+                        errors[next][e][i] += error * 5;
+                        errors[next][e + 1][i] += error;
+                    }
+                    DitherMode::Atkinson => {
+                        // An eighth each to six neighbours.
+                        for (row, column) in [
+                            (current, e + 1),
+                            (current, e + 2),
+                            (next, e - 1),
+                            (next, e),
+                            (next, e + 1),
+                            (after, e),
+                        ] {
+                            errors[row][column][i] += error * 2;
+                        }
+                    }
+                }
+            }
+            let shift = if x % 2 == 0 { 4 } else { 0 };
+            out[x / 2] = out[x / 2] & !(0xF << shift) | (color as u8) << shift;
+        }
+        // This row becomes the one after next.
+        errors[current].fill([0; 3]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    fn header(width: i32, height: i32, bits_per_pixel: u16) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend_from_slice(b"BM");
+        header.extend_from_slice(&0u32.to_le_bytes()); // file size
+        header.extend_from_slice(&0u32.to_le_bytes()); // reserved
+        header.extend_from_slice(&54u32.to_le_bytes()); // data offset
+        header.extend_from_slice(&40u32.to_le_bytes()); // info header size
+        header.extend_from_slice(&width.to_le_bytes());
+        header.extend_from_slice(&height.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // planes
+        header.extend_from_slice(&bits_per_pixel.to_le_bytes());
+        header.resize(BMP_HEADER_LENGTH, 0);
+        header
+    }
+
+    #[test]
+    fn parses_headers() {
+        assert_eq!(
+            Bmp::parse(&header(800, 480, 24)),
+            Ok(Bmp {
+                width: 800,
+                height: 480,
+                top_down: false,
+                data_offset: 54,
+            })
+        );
+        assert_eq!(
+            Bmp::parse(&header(640, -480, 24)).map(|bmp| (bmp.height, bmp.top_down)),
+            Ok((480, true))
+        );
+        assert_eq!(Bmp::parse(&header(800, 480, 8)), Err(BmpError::Unsupported));
+        assert_eq!(Bmp::parse(&header(0, 480, 24)), Err(BmpError::NotBmp));
+        assert_eq!(Bmp::parse(b"GIF89a"), Err(BmpError::NotBmp));
+        let mut png = header(800, 480, 24);
+        png[0] = 0x89;
+        assert_eq!(Bmp::parse(&png), Err(BmpError::NotBmp));
+    }
+
+    #[test]
+    fn rejects_huge_images() {
+        // 0x5555_5556 * 3 wraps around to 2 in a u32.
+        for (width, height) in [(0x5555_5556, 480), (i32::MAX, 1), (800, -i32::MAX)] {
+            assert_eq!(
+                Bmp::parse(&header(width, height, 24)),
+                Err(BmpError::TooLarge)
+            );
+        }
+        let largest = MAX_BMP_SIZE as i32;
+        let bmp = Bmp::parse(&header(largest, largest, 24)).unwrap();
+        let last = bmp.row_span(HEIGHT - 1).unwrap();
+        assert_eq!(last.pixels, WIDTH);
+
+        let mut far_off = header(800, 480, 24);
+        far_off[10..14].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(Bmp::parse(&far_off), Err(BmpError::NotBmp));
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        // 101 pixels * 3 bytes is padded to 304 bytes per row.
+        let bmp = Bmp::parse(&header(101, 80, 24)).unwrap();
+        let end = 54 + 79 * 304 + 303;
+        assert_eq!(bmp.check_length(end + 1), Ok(()));
+        assert_eq!(bmp.check_length(end), Ok(()));
+        assert_eq!(bmp.check_length(end - 1), Err(BmpError::Truncated));
+        // Just the header.
+        assert_eq!(bmp.check_length(54), Err(BmpError::Truncated));
+    }
+
+    #[test]
+    fn full_size_rows() {
+        let bmp = Bmp::parse(&header(800, 480, 24)).unwrap();
+        // Bottom-up, so the top row is last in the file.
+        assert_eq!(
+            bmp.row_span(0),
+            Some(RowSpan {
+                offset: 54 + 479 * 2400,
+                pixels: 800,
+                x: 0,
+            })
+        );
+        assert_eq!(bmp.row_span(479).map(|span| span.offset), Some(54));
+        assert_eq!(bmp.row_span(480), None);
+    }
+
+    #[test]
+    fn small_images_are_centered() {
+        // 101 pixels * 3 bytes is padded to 304 bytes per row.
+        let bmp = Bmp::parse(&header(101, -80, 24)).unwrap();
+        assert_eq!(bmp.row_span(199), None);
+        assert_eq!(
+            bmp.row_span(200),
+            Some(RowSpan {
+                offset: 54,
+                pixels: 101,
+                x: 349,
+            })
+        );
+        assert_eq!(bmp.row_span(201).map(|span| span.offset), Some(54 + 304));
+        assert_eq!(
+            bmp.row_span(279).map(|span| span.offset),
+            Some(54 + 79 * 304)
+        );
+        assert_eq!(bmp.row_span(280), None);
+    }
+
+    #[test]
+    fn large_images_are_cropped() {
+        let bmp = Bmp::parse(&header(1000, -500, 24)).unwrap();
+        assert_eq!(
+            bmp.row_span(0),
+            Some(RowSpan {
+                offset: 54 + 10 * 3000 + 100 * 3,
+                pixels: 800,
+                x: 0,
+            })
+        );
+        assert_eq!(
+            bmp.row_span(479).map(|span| span.offset),
+            Some(54 + 489 * 3000 + 300)
+        );
+    }
+
+    fn dither(mode: DitherMode, bgr: [u8; 3]) -> Vec<u8> {
+        let row: Vec<u8> = bgr.iter().copied().cycle().take(WIDTH * 3).collect();
+        let mut ditherer = Ditherer::new(mode);
+        let mut out = [0; FRAME_ROW_BYTES];
+        let mut pixels = Vec::new();
+        for _ in 0..4 {
+            ditherer.dither_row(&row, &mut out);
+            pixels.extend(out.iter().flat_map(|byte| [byte >> 4, byte & 0xF]));
+        }
+        pixels
+    }
+
+    #[test]
+    fn palette_colors_are_exact() {
+        for mode in [
+            DitherMode::None,
+            DitherMode::FloydSteinberg,
+            DitherMode::Atkinson,
+        ] {
+            for (color, rgb) in PALETTE {
+                let bgr = [rgb[2] as u8, rgb[1] as u8, rgb[0] as u8];
+                assert!(dither(mode, bgr).iter().all(|&pixel| pixel == color as u8));
+            }
+        }
+    }
+
+    #[test]
+    fn colors_are_mixed_to_match_on_average() {
+        // These are all colors the palette can mix. Anything outside it can only be approximated.
+        for bgr in [[128, 128, 128], [40, 90, 200], [100, 150, 200]] {
+            let pixels = dither(DitherMode::FloydSteinberg, bgr);
+            for (channel, &expected) in bgr.iter().rev().enumerate() {
+                let total: i32 = pixels
+                    .iter()
+                    .map(|&pixel| PALETTE[pixel as usize].1[channel] as i32)
+                    .sum();
+                let average = total / pixels.len() as i32;
+                assert!(
+                    average.abs_diff(expected as i32) < 16,
+                    "{:?}: {} vs {}",
+                    bgr,
+                    average,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn atkinson_mixes_colors() {
+        let pixels = dither(DitherMode::Atkinson, [128, 128, 128]);
+        assert!(pixels.iter().any(|&pixel| pixel != pixels[0]));
+    }
+
+    #[test]
+    fn ordered_dithering_repeats() {
+        let pixels = dither(DitherMode::Ordered4x4, [128, 128, 128]);
+        assert!(pixels.iter().any(|&pixel| pixel != pixels[0]));
+        for (i, &pixel) in pixels.iter().enumerate().skip(4) {
+            if i % WIDTH >= 4 {
+                assert_eq!(pixel, pixels[i - 4]);
+            }
+        }
+    }
+
+    #[test]
+    fn without_dithering_colors_are_rounded() {
+        let pixels = dither(DitherMode::None, [20, 100, 230]);
+        assert!(pixels.iter().all(|&pixel| pixel == Color::Orange as u8));
+    }
+
+    #[test]
+    fn modes_round_trip() {
+        for mode in DitherMode::ALL {
+            assert_eq!(DitherMode::from_bits(mode.to_bits()), Some(mode));
+        }
+        assert_eq!(DitherMode::from_bits(DitherMode::ALL.len() as u8), None);
+        assert_eq!(DitherMode::from_bits(0xFF), None);
+    }
+}
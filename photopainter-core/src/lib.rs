@@ -0,0 +1,19 @@
+//! Hardware-independent parts of the PhotoPainter firmware.
+//!
+//! Nothing in here touches the RP2040 directly. Drivers are written against the `embedded-hal`
+//! traits, so everything builds for the host as well and can be tested with `cargo test-host`.
+#![no_std]
+
+pub mod battery;
+pub mod config;
+pub mod console;
+pub mod datetime;
+pub mod frame;
+pub mod image;
+pub mod mode;
+pub mod power;
+pub mod rtc;
+pub mod scheduler;
+pub mod slideshow;
+pub mod storage;
+pub mod ymodem;
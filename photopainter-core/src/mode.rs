@@ -0,0 +1,170 @@
+// The firmware's top-level state machine.
+//
+// Each state has an action in main.rs that talks to the hardware and reports what it found as an
+// `Event`; the transitions themselves are plain data so they can be tested on the host. Adding a
+// state means adding a variant here and an arm in the firmware's main loop.
+
+use crate::datetime::DateTime;
+use crate::power::ShutdownReason;
+
+/// What the firmware is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum State {
+    /// Work out whether we are on USB power, and whether the battery can take a refresh.
+    CheckPower,
+    /// Redraw the display.
+    Render,
+    /// Arm the RTC for the next refresh.
+    Schedule,
+    /// Stay awake on USB power, serving the console and waiting for the button or the RTC alarm.
+    ConsoleIdle,
+    /// Power off. This is the final state.
+    Shutdown(ShutdownReason),
+}
+
+/// The outcome of a state's action.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// The power source has been checked.
+    Power { on_usb: bool, battery_low: bool },
+    /// The display has been redrawn.
+    Rendered,
+    /// The next refresh has been scheduled.
+    Scheduled { on_usb: bool },
+    /// The button was pressed or the RTC alarm fired.
+    RefreshRequested,
+    /// USB power went away.
+    UsbDisconnected,
+    /// The console asked to power off until the given time.
+    SleepRequested(DateTime),
+    /// Nothing happened.
+    Idle,
+}
+
+impl State {
+    /// Returns the state to move to after `event`.
+    ///
+    /// Events that make no sense in the current state leave it unchanged.
+    pub fn next(self, event: Event) -> State {
+        match (self, event) {
+            (State::CheckPower, Event::Power { on_usb: true, .. }) => State::Schedule,
+            (
+                State::CheckPower,
+                Event::Power {
+                    on_usb: false,
+                    battery_low: true,
+                },
+            ) => State::Shutdown(ShutdownReason::LowBattery),
+            (State::CheckPower, Event::Power { .. }) => State::Render,
+            (State::Render, Event::Rendered) => State::Schedule,
+            (State::Schedule, Event::Scheduled { on_usb: true }) => State::ConsoleIdle,
+            (State::Schedule, Event::Scheduled { on_usb: false }) => {
+                State::Shutdown(ShutdownReason::RefreshDone)
+            }
+            (State::ConsoleIdle, Event::RefreshRequested) => State::Render,
+            (State::ConsoleIdle, Event::UsbDisconnected) => {
+                State::Shutdown(ShutdownReason::UsbDisconnected)
+            }
+            (State::ConsoleIdle, Event::SleepRequested(at)) => {
+                State::Shutdown(ShutdownReason::SleepUntil(at))
+            }
+            (state, _) => state,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(events: &[Event]) -> State {
+        events
+            .iter()
+            .fold(State::CheckPower, |state, &event| state.next(event))
+    }
+
+    #[test]
+    fn battery_refresh() {
+        let state = run(&[
+            Event::Power {
+                on_usb: false,
+                battery_low: false,
+            },
+            Event::Rendered,
+            Event::Scheduled { on_usb: false },
+        ]);
+        assert_eq!(state, State::Shutdown(ShutdownReason::RefreshDone));
+    }
+
+    #[test]
+    fn low_battery() {
+        let state = run(&[Event::Power {
+            on_usb: false,
+            battery_low: true,
+        }]);
+        assert_eq!(state, State::Shutdown(ShutdownReason::LowBattery));
+    }
+
+    #[test]
+    fn usb_power() {
+        // A low battery doesn't matter while it's charging.
+        let power = Event::Power {
+            on_usb: true,
+            battery_low: true,
+        };
+        assert_eq!(run(&[power]), State::Schedule);
+        assert_eq!(
+            run(&[power, Event::Scheduled { on_usb: true }]),
+            State::ConsoleIdle
+        );
+        assert_eq!(
+            run(&[
+                power,
+                Event::Scheduled { on_usb: true },
+                Event::Idle,
+                Event::RefreshRequested,
+                Event::Rendered,
+                Event::Scheduled { on_usb: true },
+            ]),
+            State::ConsoleIdle
+        );
+        assert_eq!(
+            run(&[
+                power,
+                Event::Scheduled { on_usb: true },
+                Event::UsbDisconnected
+            ]),
+            State::Shutdown(ShutdownReason::UsbDisconnected)
+        );
+    }
+
+    #[test]
+    fn sleep_from_the_console() {
+        let wake = DateTime {
+            year: 2025,
+            month: 12,
+            day: 24,
+            hours: 7,
+            minutes: 0,
+            seconds: 0,
+        };
+        assert_eq!(
+            State::ConsoleIdle.next(Event::SleepRequested(wake)),
+            State::Shutdown(ShutdownReason::SleepUntil(wake))
+        );
+        assert_eq!(
+            State::Render.next(Event::SleepRequested(wake)),
+            State::Render
+        );
+    }
+
+    #[test]
+    fn unexpected_events_are_ignored() {
+        assert_eq!(State::CheckPower.next(Event::Rendered), State::CheckPower);
+        assert_eq!(State::Render.next(Event::Idle), State::Render);
+        let shutdown = State::Shutdown(ShutdownReason::RefreshDone);
+        assert_eq!(shutdown.next(Event::RefreshRequested), shutdown);
+    }
+}
@@ -0,0 +1,498 @@
+// Power-off and wake-up decisions.
+//
+// The firmware only stays on long enough to refresh the display, so what it leaves the RTC alarm
+// set to before cutting the power is what decides whether the frame ever wakes up again. This is
+// written against the `Rtc` and `PowerControl` traits so the scenarios can be tested on the host.
+
+use core::fmt;
+
+use crate::datetime::{DateTime, SECONDS_PER_DAY};
+use crate::rtc::Rtc;
+use crate::scheduler::Schedule;
+
+/// Longest sleep that can be requested. The RTC alarm only matches the day of the month, so
+/// anything a month or more ahead would go off early.
+pub const MAX_SLEEP_SECONDS: u32 = 28 * SECONDS_PER_DAY;
+
+/// Something that can switch the board off.
+pub trait PowerControl {
+    /// Cuts the battery power. Returns if the board is powered some other way, e.g. over USB.
+    fn power_off(&mut self);
+}
+
+/// Why the firmware is turning the power off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ShutdownReason {
+    /// The scheduled refresh is done.
+    RefreshDone,
+    /// The battery is too low to refresh safely.
+    LowBattery,
+    /// USB power went away while we were running off it.
+    UsbDisconnected,
+    /// Asked to sleep until the given time.
+    SleepUntil(DateTime),
+}
+
+/// Why a requested wake-up time can't be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeTimeError {
+    NotInFuture,
+    TooFarAhead,
+}
+
+impl fmt::Display for WakeTimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WakeTimeError::NotInFuture => f.write_str("wake time is not in the future"),
+            WakeTimeError::TooFarAhead => f.write_str("wake time is more than 28 days ahead"),
+        }
+    }
+}
+
+/// Checks that the RTC alarm can wake us up at `at`.
+pub fn check_wake_time(now: &DateTime, at: &DateTime) -> Result<(), WakeTimeError> {
+    let seconds = at
+        .to_timestamp()
+        .checked_sub(now.to_timestamp())
+        .filter(|&seconds| seconds > 0)
+        .ok_or(WakeTimeError::NotInFuture)?;
+    if seconds > MAX_SLEEP_SECONDS {
+        return Err(WakeTimeError::TooFarAhead);
+    }
+    Ok(())
+}
+
+/// When the RTC alarm should wake us up after shutting down for `reason`, or `None` if it
+/// shouldn't.
+pub fn wake_time(reason: ShutdownReason, now: &DateTime, schedule: &Schedule) -> Option<DateTime> {
+    match reason {
+        ShutdownReason::LowBattery => None,
+        ShutdownReason::RefreshDone | ShutdownReason::UsbDisconnected => {
+            schedule.next(now).map(|(at, _)| at)
+        }
+        ShutdownReason::SleepUntil(at) => Some(at),
+    }
+}
+
+/// Programs the RTC alarm for the next wake-up (if any) and then cuts the power.
+///
+/// Every power-off path goes through here, so that the alarm is always left in a known state. The
+/// power is cut even if the RTC can't be programmed: the button still wakes the frame up.
+pub fn shutdown(
+    rtc: &mut impl Rtc,
+    power: &mut impl PowerControl,
+    reason: ShutdownReason,
+    schedule: &Schedule,
+) {
+    #[cfg(feature = "defmt")]
+    defmt::info!("Shutting down: {}", reason);
+
+    let alarm_set = match reason {
+        // Don't wake up again until someone charges the battery and presses the button.
+        ShutdownReason::LowBattery => rtc.disable_alarm().is_ok(),
+        ShutdownReason::RefreshDone | ShutdownReason::UsbDisconnected => {
+            match rtc
+                .get_datetime()
+                .map(|now| wake_time(reason, &now, schedule))
+            {
+                Ok(Some(wake)) => {
+                    #[cfg(feature = "defmt")]
+                    defmt::info!("Next wake-up: {}", wake);
+                    rtc.set_alarm(&wake).is_ok()
+                }
+                Ok(None) | Err(_) => false,
+            }
+        }
+        ShutdownReason::SleepUntil(wake) => {
+            #[cfg(feature = "defmt")]
+            defmt::info!("Next wake-up: {}", wake);
+            rtc.set_alarm(&wake).is_ok()
+        }
+    };
+    if !alarm_set {
+        #[cfg(feature = "defmt")]
+        defmt::error!("Failed to program the RTC alarm");
+    }
+
+    power.power_off();
+}
+
+/// Sets the RTC alarm for the next entry in `schedule`.
+///
+/// Returns false if the clock isn't set or can't be reached. The alarm is then disabled, so that
+/// one that has already gone off doesn't keep asking for refreshes.
+pub fn schedule_next_refresh(rtc: &mut impl Rtc, schedule: &Schedule) -> bool {
+    let scheduled = match rtc.get_datetime().map(|now| schedule.next(&now)) {
+        Ok(Some((at, _))) => {
+            #[cfg(feature = "defmt")]
+            defmt::info!("Next refresh: {}", at);
+            rtc.set_alarm(&at).is_ok()
+        }
+        Ok(None) | Err(_) => false,
+    };
+    if !scheduled {
+        #[cfg(feature = "defmt")]
+        defmt::error!("Failed to program the RTC alarm");
+        if rtc.disable_alarm().is_err() {
+            #[cfg(feature = "defmt")]
+            defmt::error!("Failed to disable the RTC alarm");
+        }
+    }
+    scheduled
+}
+
+/// What the RTC's RAM byte carries from one wake to the next. It outlives the battery being
+/// switched off, so it is the only place the firmware can leave notes for itself.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WakeRecord {
+    /// The battery was too low at the last power check.
+    pub battery_low: bool,
+    /// The last wake skipped RTC init, because the alarm turned the frame on.
+    pub fast_boot: bool,
+    /// How long the last wake lasted, up to `MAX_AWAKE_SECONDS`.
+    pub awake_seconds: u8,
+}
+
+/// Longest awake time a `WakeRecord` can hold; longer wakes are recorded as this.
+pub const MAX_AWAKE_SECONDS: u8 = 0x3F;
+
+const RECORD_BATTERY_LOW: u8 = 0x01;
+const RECORD_FAST_BOOT: u8 = 0x02;
+const RECORD_AWAKE_SHIFT: u32 = 2;
+
+impl WakeRecord {
+    pub fn from_byte(byte: u8) -> WakeRecord {
+        WakeRecord {
+            battery_low: byte & RECORD_BATTERY_LOW != 0,
+            fast_boot: byte & RECORD_FAST_BOOT != 0,
+            awake_seconds: byte >> RECORD_AWAKE_SHIFT,
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        let mut byte = self.awake_seconds.min(MAX_AWAKE_SECONDS) << RECORD_AWAKE_SHIFT;
+        if self.battery_low {
+            byte |= RECORD_BATTERY_LOW;
+        }
+        if self.fast_boot {
+            byte |= RECORD_FAST_BOOT;
+        }
+        byte
+    }
+
+    /// Records a wake that lasted `millis`, rounded to the nearest second.
+    pub fn set_awake_millis(&mut self, millis: u64) {
+        self.awake_seconds = ((millis + 500) / 1000).min(MAX_AWAKE_SECONDS as u64) as u8;
+    }
+}
+
+/// Describes the last wake, e.g. "12 s, fast boot".
+impl fmt::Display for WakeRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.awake_seconds >= MAX_AWAKE_SECONDS {
+            write!(f, "{} s or more", MAX_AWAKE_SECONDS)?;
+        } else {
+            write!(f, "{} s", self.awake_seconds)?;
+        }
+        f.write_str(if self.fast_boot {
+            ", fast boot"
+        } else {
+            ", full boot"
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::format;
+
+    /// An RTC that keeps its state in memory, and can be made to fail.
+    #[derive(Default)]
+    struct FakeRtc {
+        now: Option<DateTime>,
+        alarm: Option<DateTime>,
+        broken: bool,
+    }
+
+    impl Rtc for FakeRtc {
+        type Error = ();
+
+        fn get_datetime(&mut self) -> Result<DateTime, ()> {
+            if self.broken {
+                return Err(());
+            }
+            self.now.ok_or(())
+        }
+
+        fn set_datetime(&mut self, datetime: &DateTime) -> Result<(), ()> {
+            if self.broken {
+                return Err(());
+            }
+            self.now = Some(*datetime);
+            Ok(())
+        }
+
+        fn set_alarm(&mut self, at: &DateTime) -> Result<(), ()> {
+            if self.broken {
+                return Err(());
+            }
+            self.alarm = Some(*at);
+            Ok(())
+        }
+
+        fn disable_alarm(&mut self) -> Result<(), ()> {
+            if self.broken {
+                return Err(());
+            }
+            self.alarm = None;
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct FakePower {
+        off: bool,
+    }
+
+    impl PowerControl for FakePower {
+        fn power_off(&mut self) {
+            self.off = true;
+        }
+    }
+
+    fn noon() -> DateTime {
+        DateTime {
+            year: 2024,
+            month: 12,
+            day: 31,
+            hours: 12,
+            minutes: 0,
+            seconds: 0,
+        }
+    }
+
+    #[test]
+    fn refresh_done_wakes_up_at_the_next_entry() {
+        let mut rtc = FakeRtc {
+            now: Some(noon()),
+            ..Default::default()
+        };
+        let mut power = FakePower::default();
+        shutdown(
+            &mut rtc,
+            &mut power,
+            ShutdownReason::RefreshDone,
+            &Schedule::default(),
+        );
+        assert!(power.off);
+        assert_eq!(
+            rtc.alarm,
+            Some(DateTime {
+                hours: 18,
+                ..noon()
+            })
+        );
+    }
+
+    #[test]
+    fn low_battery_disables_the_alarm() {
+        let mut rtc = FakeRtc {
+            now: Some(noon()),
+            alarm: Some(noon()),
+            ..Default::default()
+        };
+        let mut power = FakePower::default();
+        shutdown(
+            &mut rtc,
+            &mut power,
+            ShutdownReason::LowBattery,
+            &Schedule::default(),
+        );
+        assert!(power.off);
+        assert_eq!(rtc.alarm, None);
+    }
+
+    #[test]
+    fn sleep_until() {
+        let mut rtc = FakeRtc {
+            now: Some(noon()),
+            ..Default::default()
+        };
+        let mut power = FakePower::default();
+        let wake = DateTime {
+            hours: 18,
+            ..noon()
+        };
+        shutdown(
+            &mut rtc,
+            &mut power,
+            ShutdownReason::SleepUntil(wake),
+            &Schedule::default(),
+        );
+        assert!(power.off);
+        assert_eq!(rtc.alarm, Some(wake));
+    }
+
+    #[test]
+    fn planned_wake_times() {
+        let schedule = Schedule::parse(b"09:00 slideshow").unwrap();
+        let tomorrow = DateTime {
+            year: 2025,
+            month: 1,
+            day: 1,
+            hours: 9,
+            ..noon()
+        };
+        assert_eq!(
+            wake_time(ShutdownReason::RefreshDone, &noon(), &schedule),
+            Some(tomorrow)
+        );
+        assert_eq!(
+            wake_time(ShutdownReason::UsbDisconnected, &noon(), &schedule),
+            Some(tomorrow)
+        );
+        assert_eq!(
+            wake_time(ShutdownReason::LowBattery, &noon(), &schedule),
+            None
+        );
+        let later = DateTime {
+            hours: 15,
+            ..noon()
+        };
+        assert_eq!(
+            wake_time(ShutdownReason::SleepUntil(later), &noon(), &schedule),
+            Some(later)
+        );
+    }
+
+    #[test]
+    fn wake_times() {
+        let now = noon();
+        let later = DateTime::from_timestamp(now.to_timestamp() + 1);
+        assert_eq!(check_wake_time(&now, &later), Ok(()));
+        let latest = DateTime::from_timestamp(now.to_timestamp() + MAX_SLEEP_SECONDS);
+        assert_eq!(check_wake_time(&now, &latest), Ok(()));
+        let too_late = DateTime::from_timestamp(now.to_timestamp() + MAX_SLEEP_SECONDS + 1);
+        assert_eq!(
+            check_wake_time(&now, &too_late),
+            Err(WakeTimeError::TooFarAhead)
+        );
+        let earlier = DateTime::from_timestamp(now.to_timestamp() - 1);
+        assert_eq!(
+            check_wake_time(&now, &earlier),
+            Err(WakeTimeError::NotInFuture)
+        );
+        assert_eq!(check_wake_time(&now, &now), Err(WakeTimeError::NotInFuture));
+    }
+
+    #[test]
+    fn rtc_failure_still_powers_off() {
+        for reason in [
+            ShutdownReason::RefreshDone,
+            ShutdownReason::LowBattery,
+            ShutdownReason::UsbDisconnected,
+            ShutdownReason::SleepUntil(noon()),
+        ] {
+            let mut rtc = FakeRtc {
+                broken: true,
+                ..Default::default()
+            };
+            let mut power = FakePower::default();
+            shutdown(&mut rtc, &mut power, reason, &Schedule::default());
+            assert!(power.off);
+        }
+    }
+
+    #[test]
+    fn unset_clock_still_powers_off() {
+        let mut rtc = FakeRtc::default();
+        let mut power = FakePower::default();
+        shutdown(
+            &mut rtc,
+            &mut power,
+            ShutdownReason::UsbDisconnected,
+            &Schedule::default(),
+        );
+        assert!(power.off);
+        assert_eq!(rtc.alarm, None);
+    }
+
+    #[test]
+    fn next_refresh() {
+        let schedule = Schedule::default();
+        let mut rtc = FakeRtc {
+            now: Some(DateTime {
+                hours: 19,
+                ..noon()
+            }),
+            ..Default::default()
+        };
+        // The default schedule redraws the calendar at midnight.
+        assert!(schedule_next_refresh(&mut rtc, &schedule));
+        assert_eq!(
+            rtc.alarm,
+            Some(DateTime {
+                year: 2025,
+                month: 1,
+                day: 1,
+                hours: 0,
+                minutes: 0,
+                seconds: 0,
+            })
+        );
+
+        rtc.broken = true;
+        assert!(!schedule_next_refresh(&mut rtc, &schedule));
+    }
+
+    #[test]
+    fn next_refresh_failure_clears_the_alarm() {
+        // The alarm that just went off, with the clock since lost.
+        let mut rtc = FakeRtc {
+            alarm: Some(noon()),
+            ..Default::default()
+        };
+        assert!(!schedule_next_refresh(&mut rtc, &Schedule::default()));
+        assert_eq!(rtc.alarm, None);
+
+        // Or with no next entry that the RTC can hold.
+        let mut rtc = FakeRtc {
+            now: Some(DateTime {
+                year: 2099,
+                hours: 19,
+                ..noon()
+            }),
+            alarm: Some(noon()),
+            ..Default::default()
+        };
+        assert!(!schedule_next_refresh(&mut rtc, &Schedule::default()));
+        assert_eq!(rtc.alarm, None);
+    }
+
+    #[test]
+    fn wake_records() {
+        let mut record = WakeRecord {
+            battery_low: true,
+            fast_boot: true,
+            awake_seconds: 0,
+        };
+        record.set_awake_millis(11_600);
+        assert_eq!(record.awake_seconds, 12);
+        assert_eq!(WakeRecord::from_byte(record.to_byte()), record);
+        assert_eq!(format!("{}", record), "12 s, fast boot");
+
+        // Long wakes, e.g. on USB power, saturate.
+        record.set_awake_millis(10 * 60 * 1000);
+        record.fast_boot = false;
+        assert_eq!(WakeRecord::from_byte(record.to_byte()), record);
+        assert_eq!(format!("{}", record), "63 s or more, full boot");
+
+        // The battery flag is where it was before the record grew.
+        assert!(WakeRecord::from_byte(0x01).battery_low);
+        assert_eq!(WakeRecord::from_byte(0x00), WakeRecord::default());
+    }
+}
@@ -0,0 +1,609 @@
+// Driver for the PCF85063 real-time clock.
+//
+// The driver only depends on the `embedded-hal` I2C and delay traits, so it works with any HAL
+// and can be exercised on the host against a mock bus.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::{Error as I2cError, ErrorKind, I2c};
+
+pub use crate::datetime::DateTime;
+
+// NOTE: Borrowed lots of ideas and code snippets from https://github.com/tweedegolf/pcf85063a.
+// Datasheet: https://www.nxp.com/docs/en/data-sheet/PCF85063A.pdf
+
+#[derive(Debug)]
+pub enum Error<E> {
+    /// I2C bus error
+    I2C(E),
+    /// Invalid input data
+    InvalidInputData,
+    /// A time component was out of range
+    ComponentRange,
+}
+
+// pub const OFFSET: u8 = 0x02;
+
+// // timer registers
+// pub const TIMER_VALUE: u8 = 0x10;
+// pub const TIMER_MODE: u8 = 0x11;
+
+const DEVICE_ADDRESS: u8 = 0b1010001;
+
+// Number of times a transaction is attempted before a bus error is reported. A single glitch
+// shouldn't be enough to lose the alarm and leave the frame asleep forever.
+const MAX_ATTEMPTS: usize = 3;
+// How long to wait before each retry, so that whatever upset the bus has time to pass.
+const RETRY_DELAY_MS: u32 = 1;
+
+// After a reset, how long to wait before checking again that the oscillator has started. The wait
+// doubles each time, up to the maximum, until the timeout. Crystals usually start within a few
+// hundred milliseconds, so fixed waits of half a second mostly waste battery.
+const OSCILLATOR_POLL_MS: u32 = 10;
+const OSCILLATOR_MAX_POLL_MS: u32 = 160;
+const OSCILLATOR_TIMEOUT_MS: u32 = 3_000;
+
+// Control and status registers.
+const REG_CONTROL_1: u8 = 0x00;
+const REG_CONTROL_2: u8 = 0x01;
+// Free RAM byte.
+const REG_RAM_BYTE: u8 = 0x03;
+// Time and date registers.
+const REG_SECONDS: u8 = 0x04;
+// Alarm registers (second, minute, hour, day and weekday are consecutive).
+const REG_SECOND_ALARM: u8 = 0x0B;
+
+// REG_CONTROL_1 values.
+const CONTROL_1_DEVICE_RESET: u8 = 0x58;
+
+// REG_CONTROL_2 values.
+const CONTROL_2_ALARM_INTERRUPT_ENABLE: u8 = 0x80;
+const CONTROL_2_ALARM_FLAG: u8 = 0x40;
+const CONTROL_2_CLKOUT_MASK: u8 = 0x07;
+
+// Alarm register values.
+const ALARM_DISABLED: u8 = 0x80;
+
+// REG_SECONDS values.
+const SECONDS_OSCILLATOR_STOP: u8 = 0x80;
+const SECONDS_VALUE_MASK: u8 = 0x7F;
+
+// Masks for the BCD fields of the time and date registers.
+const MINUTES_VALUE_MASK: u8 = 0x7F;
+const HOURS_VALUE_MASK: u8 = 0x3F;
+const DAYS_VALUE_MASK: u8 = 0x3F;
+const WEEKDAYS_VALUE_MASK: u8 = 0x07;
+const MONTHS_VALUE_MASK: u8 = 0x1F;
+
+fn is_transient(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::Bus | ErrorKind::ArbitrationLoss | ErrorKind::NoAcknowledge(_)
+    )
+}
+
+fn bcd_to_decimal(bcd: u8) -> Option<u8> {
+    let (tens, units) = (bcd >> 4, bcd & 0x0F);
+    if tens > 9 || units > 9 {
+        return None;
+    }
+    Some(tens * 10 + units)
+}
+
+fn decimal_to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// The values `set_alarm` writes to the five alarm registers, starting at Second_alarm.
+///
+/// The weekday alarm is left disabled, so the alarm goes off when the day of the month and the
+/// time of day match.
+pub fn alarm_registers(at: &DateTime) -> [u8; 5] {
+    [
+        decimal_to_bcd(at.seconds),
+        decimal_to_bcd(at.minutes),
+        decimal_to_bcd(at.hours),
+        decimal_to_bcd(at.day),
+        ALARM_DISABLED,
+    ]
+}
+
+/// The clock operations that the power and scheduling logic relies on.
+///
+/// Implemented by the PCF85063 driver, and by simple fakes in tests.
+pub trait Rtc {
+    type Error;
+
+    fn get_datetime(&mut self) -> Result<DateTime, Self::Error>;
+    fn set_datetime(&mut self, datetime: &DateTime) -> Result<(), Self::Error>;
+    fn set_alarm(&mut self, at: &DateTime) -> Result<(), Self::Error>;
+    fn disable_alarm(&mut self) -> Result<(), Self::Error>;
+}
+
+/// Frequency of the square wave on the CLKOUT pin.
+///
+/// The device comes out of reset driving 32.768 kHz, which costs current for no benefit unless
+/// something is actually connected to the pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClkoutFrequency {
+    Hz32768 = 0,
+    Hz16384 = 1,
+    Hz8192 = 2,
+    Hz4096 = 3,
+    Hz2048 = 4,
+    Hz1024 = 5,
+    Hz1 = 6,
+    /// CLKOUT is held low.
+    Disabled = 7,
+}
+
+#[derive(Debug, Default)]
+pub struct PCF85063<I2C, D> {
+    /// The concrete I2C device implementation.
+    i2c: I2C,
+    /// For waiting on the oscillator, and between retries.
+    delay: D,
+}
+
+impl<I2C, D, E> PCF85063<I2C, D>
+where
+    I2C: I2c<Error = E>,
+    D: DelayNs,
+    E: I2cError,
+{
+    pub fn new(i2c: I2C, delay: D) -> Self {
+        PCF85063 { i2c, delay }
+    }
+
+    /// Gets the RTC ready to use.
+    ///
+    /// If the oscillator stop flag is clear, the clock has kept running since it was last
+    /// checked, so the time and RAM byte are kept and this is quick. Otherwise the device is reset,
+    /// and this waits for the oscillator to start.
+    pub fn init_device(&mut self) -> Result<(), Error<E>> {
+        if self.read_register(REG_SECONDS)? & SECONDS_OSCILLATOR_STOP != 0 {
+            self.write_register(REG_CONTROL_1, CONTROL_1_DEVICE_RESET)?;
+            self.wait_for_oscillator()?;
+        }
+        // Keep the CLKOUT selection, which `set_clkout` may have changed on an earlier wake.
+        let control = self.read_register(REG_CONTROL_2)?;
+        self.write_register(
+            REG_CONTROL_2,
+            (control | CONTROL_2_ALARM_INTERRUPT_ENABLE) & !CONTROL_2_ALARM_FLAG,
+        )
+    }
+
+    /// Clears the oscillator stop flag until it stays clear, backing off between attempts.
+    fn wait_for_oscillator(&mut self) -> Result<(), Error<E>> {
+        let mut waited_ms = 0;
+        let mut wait_ms = OSCILLATOR_POLL_MS;
+        loop {
+            let sec = self.read_register(REG_SECONDS)?;
+            if sec & SECONDS_OSCILLATOR_STOP == 0 {
+                return Ok(());
+            }
+            if waited_ms >= OSCILLATOR_TIMEOUT_MS {
+                #[cfg(feature = "defmt")]
+                defmt::info!("RTC clock stability is unknown");
+                return Ok(());
+            }
+            self.write_register(REG_SECONDS, sec & SECONDS_VALUE_MASK)?;
+            self.delay.delay_ms(wait_ms);
+            waited_ms += wait_ms;
+            wait_ms = (wait_ms * 2).min(OSCILLATOR_MAX_POLL_MS);
+        }
+    }
+
+    /// Reads the current date and time.
+    ///
+    /// Fails with `InvalidInputData` if the registers don't hold a valid date, e.g. because the
+    /// clock has never been set.
+    pub fn get_datetime(&mut self) -> Result<DateTime, Error<E>> {
+        // Seconds through years are consecutive registers, and reading them in one transaction
+        // guarantees a consistent snapshot.
+        let mut data = [0; 7];
+        self.with_retries(|i2c| i2c.write_read(DEVICE_ADDRESS, &[REG_SECONDS], &mut data))?;
+        let field =
+            |value: u8, mask: u8| bcd_to_decimal(value & mask).ok_or(Error::InvalidInputData);
+        let datetime = DateTime {
+            seconds: field(data[0], SECONDS_VALUE_MASK)?,
+            minutes: field(data[1], MINUTES_VALUE_MASK)?,
+            hours: field(data[2], HOURS_VALUE_MASK)?,
+            day: field(data[3], DAYS_VALUE_MASK)?,
+            month: field(data[5], MONTHS_VALUE_MASK)?,
+            year: 2000 + field(data[6], 0xFF)? as u16,
+        };
+        if !datetime.is_valid() {
+            return Err(Error::InvalidInputData);
+        }
+        Ok(datetime)
+    }
+
+    /// Sets the current date and time.
+    pub fn set_datetime(&mut self, datetime: &DateTime) -> Result<(), Error<E>> {
+        if !datetime.is_valid() {
+            return Err(Error::ComponentRange);
+        }
+        let payload: [u8; 8] = [
+            REG_SECONDS,
+            decimal_to_bcd(datetime.seconds),
+            decimal_to_bcd(datetime.minutes),
+            decimal_to_bcd(datetime.hours),
+            decimal_to_bcd(datetime.day),
+            datetime.weekday() & WEEKDAYS_VALUE_MASK,
+            decimal_to_bcd(datetime.month),
+            decimal_to_bcd((datetime.year - 2000) as u8),
+        ];
+        self.with_retries(|i2c| i2c.write(DEVICE_ADDRESS, &payload))
+    }
+
+    /// Arms the alarm for the given day of the month and time of day, and clears any previous
+    /// alarm.
+    ///
+    /// The month and year are ignored by the hardware, so this only makes sense for alarms less
+    /// than a month away.
+    pub fn set_alarm(&mut self, at: &DateTime) -> Result<(), Error<E>> {
+        if !at.is_valid() {
+            return Err(Error::ComponentRange);
+        }
+        let mut payload = [0; 6];
+        payload[0] = REG_SECOND_ALARM;
+        payload[1..].copy_from_slice(&alarm_registers(at));
+        self.with_retries(|i2c| i2c.write(DEVICE_ADDRESS, &payload))?;
+        let control = self.read_register(REG_CONTROL_2)?;
+        self.write_register(
+            REG_CONTROL_2,
+            (control | CONTROL_2_ALARM_INTERRUPT_ENABLE) & !CONTROL_2_ALARM_FLAG,
+        )
+    }
+
+    /// Disables the alarm, and clears it if it has already fired.
+    pub fn disable_alarm(&mut self) -> Result<(), Error<E>> {
+        let payload: [u8; 6] = [
+            REG_SECOND_ALARM,
+            ALARM_DISABLED,
+            ALARM_DISABLED,
+            ALARM_DISABLED,
+            ALARM_DISABLED,
+            ALARM_DISABLED,
+        ];
+        self.with_retries(|i2c| i2c.write(DEVICE_ADDRESS, &payload))?;
+        let control = self.read_register(REG_CONTROL_2)?;
+        self.write_register(
+            REG_CONTROL_2,
+            control & !(CONTROL_2_ALARM_INTERRUPT_ENABLE | CONTROL_2_ALARM_FLAG),
+        )
+    }
+
+    /// Selects the CLKOUT frequency, or disables the output entirely.
+    pub fn set_clkout(&mut self, frequency: ClkoutFrequency) -> Result<(), Error<E>> {
+        let control = self.read_register(REG_CONTROL_2)?;
+        self.write_register(
+            REG_CONTROL_2,
+            (control & !CONTROL_2_CLKOUT_MASK) | frequency as u8,
+        )
+    }
+
+    /// Reads the byte of general-purpose RAM, which keeps its value while the RTC has power.
+    ///
+    /// A software reset clears it, and `init_device` resets the device if the oscillator had
+    /// stopped, so read it first.
+    pub fn read_ram_byte(&mut self) -> Result<u8, Error<E>> {
+        self.read_register(REG_RAM_BYTE)
+    }
+
+    /// Writes the byte of general-purpose RAM.
+    pub fn write_ram_byte(&mut self, value: u8) -> Result<(), Error<E>> {
+        self.write_register(REG_RAM_BYTE, value)
+    }
+
+    fn write_register(&mut self, register: u8, data: u8) -> Result<(), Error<E>> {
+        let payload: [u8; 2] = [register, data];
+        self.with_retries(|i2c| i2c.write(DEVICE_ADDRESS, &payload))
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u8, Error<E>> {
+        let mut data = [0];
+        self.with_retries(|i2c| i2c.write_read(DEVICE_ADDRESS, &[register], &mut data))
+            .and(Ok(data[0]))
+    }
+
+    /// Runs an I2C transaction, retrying it after a short wait if it fails with what looks like a
+    /// transient error.
+    ///
+    /// All transactions with the RTC are register writes or reads, so repeating one is harmless.
+    fn with_retries<T>(
+        &mut self,
+        mut transaction: impl FnMut(&mut I2C) -> Result<T, E>,
+    ) -> Result<T, Error<E>> {
+        let mut attempt = 1;
+        loop {
+            match transaction(&mut self.i2c) {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < MAX_ATTEMPTS && is_transient(e.kind()) => {
+                    #[cfg(feature = "defmt")]
+                    defmt::warn!("RTC I2C error, retrying");
+                    self.delay.delay_ms(RETRY_DELAY_MS);
+                    attempt += 1;
+                }
+                Err(e) => return Err(Error::I2C(e)),
+            }
+        }
+    }
+}
+
+impl<I2C, D, E> Rtc for PCF85063<I2C, D>
+where
+    I2C: I2c<Error = E>,
+    D: DelayNs,
+    E: I2cError,
+{
+    type Error = Error<E>;
+
+    fn get_datetime(&mut self) -> Result<DateTime, Self::Error> {
+        PCF85063::get_datetime(self)
+    }
+
+    fn set_datetime(&mut self, datetime: &DateTime) -> Result<(), Self::Error> {
+        PCF85063::set_datetime(self, datetime)
+    }
+
+    fn set_alarm(&mut self, at: &DateTime) -> Result<(), Self::Error> {
+        PCF85063::set_alarm(self, at)
+    }
+
+    fn disable_alarm(&mut self) -> Result<(), Self::Error> {
+        PCF85063::disable_alarm(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use embedded_hal::i2c::NoAcknowledgeSource;
+    use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
+    use std::vec;
+
+    const NACK: ErrorKind = ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address);
+
+    fn datetime() -> DateTime {
+        DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hours: 23,
+            minutes: 59,
+            seconds: 58,
+        }
+    }
+
+    /// Adds up the time spent waiting.
+    #[derive(Default)]
+    struct Waits {
+        ns: u64,
+    }
+
+    impl DelayNs for Waits {
+        fn delay_ns(&mut self, ns: u32) {
+            self.ns += ns as u64;
+        }
+    }
+
+    fn run<T>(expectations: &[Transaction], f: impl FnOnce(&mut PCF85063<Mock, Waits>) -> T) -> T {
+        let mut i2c = Mock::new(expectations);
+        let mut rtc = PCF85063::new(i2c.clone(), Waits::default());
+        let result = f(&mut rtc);
+        i2c.done();
+        result
+    }
+
+    #[test]
+    fn bcd() {
+        assert_eq!(bcd_to_decimal(0x59), Some(59));
+        assert_eq!(bcd_to_decimal(0x0A), None);
+        assert_eq!(bcd_to_decimal(0xA0), None);
+        for value in 0..100 {
+            assert_eq!(bcd_to_decimal(decimal_to_bcd(value)), Some(value));
+        }
+    }
+
+    #[test]
+    fn get_datetime() {
+        let read = Transaction::write_read(
+            DEVICE_ADDRESS,
+            vec![REG_SECONDS],
+            // The oscillator stop flag is set and must be ignored.
+            vec![0xD8, 0x59, 0x23, 0x29, 0x04, 0x02, 0x24],
+        );
+        let result = run(&[read], |rtc| rtc.get_datetime());
+        assert_eq!(result.unwrap(), datetime());
+    }
+
+    #[test]
+    fn get_datetime_rejects_garbage() {
+        // Registers straight after power-on: 2000-00-00 isn't a date.
+        let read = Transaction::write_read(
+            DEVICE_ADDRESS,
+            vec![REG_SECONDS],
+            vec![0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        );
+        let result = run(&[read], |rtc| rtc.get_datetime());
+        assert!(matches!(result, Err(Error::InvalidInputData)));
+    }
+
+    #[test]
+    fn set_datetime() {
+        let write = Transaction::write(
+            DEVICE_ADDRESS,
+            vec![REG_SECONDS, 0x58, 0x59, 0x23, 0x29, 0x04, 0x02, 0x24],
+        );
+        run(&[write], |rtc| rtc.set_datetime(&datetime())).unwrap();
+
+        let invalid = DateTime {
+            day: 30,
+            ..datetime()
+        };
+        let result = run(&[], |rtc| rtc.set_datetime(&invalid));
+        assert!(matches!(result, Err(Error::ComponentRange)));
+    }
+
+    #[test]
+    fn set_alarm() {
+        let expectations = [
+            Transaction::write(
+                DEVICE_ADDRESS,
+                vec![REG_SECOND_ALARM, 0x58, 0x59, 0x23, 0x29, ALARM_DISABLED],
+            ),
+            Transaction::write_read(DEVICE_ADDRESS, vec![REG_CONTROL_2], vec![0x47]),
+            Transaction::write(DEVICE_ADDRESS, vec![REG_CONTROL_2, 0x87]),
+        ];
+        run(&expectations, |rtc| rtc.set_alarm(&datetime())).unwrap();
+    }
+
+    #[test]
+    fn disable_alarm() {
+        let expectations = [
+            Transaction::write(
+                DEVICE_ADDRESS,
+                vec![REG_SECOND_ALARM, 0x80, 0x80, 0x80, 0x80, 0x80],
+            ),
+            Transaction::write_read(DEVICE_ADDRESS, vec![REG_CONTROL_2], vec![0xC7]),
+            Transaction::write(DEVICE_ADDRESS, vec![REG_CONTROL_2, 0x07]),
+        ];
+        run(&expectations, |rtc| rtc.disable_alarm()).unwrap();
+    }
+
+    #[test]
+    fn set_clkout() {
+        let expectations = [
+            Transaction::write_read(DEVICE_ADDRESS, vec![REG_CONTROL_2], vec![0x80]),
+            Transaction::write(DEVICE_ADDRESS, vec![REG_CONTROL_2, 0x87]),
+        ];
+        run(&expectations, |rtc| {
+            rtc.set_clkout(ClkoutFrequency::Disabled)
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn ram_byte() {
+        let expectations = [
+            Transaction::write(DEVICE_ADDRESS, vec![REG_RAM_BYTE, 0xA5]),
+            Transaction::write_read(DEVICE_ADDRESS, vec![REG_RAM_BYTE], vec![0xA5]),
+        ];
+        let value = run(&expectations, |rtc| {
+            rtc.write_ram_byte(0xA5)?;
+            rtc.read_ram_byte()
+        });
+        assert_eq!(value.unwrap(), 0xA5);
+    }
+
+    #[test]
+    fn init_keeps_a_running_clock() {
+        let expectations = [
+            Transaction::write_read(DEVICE_ADDRESS, vec![REG_SECONDS], vec![0x58]),
+            // The alarm has fired, and CLKOUT is disabled.
+            Transaction::write_read(DEVICE_ADDRESS, vec![REG_CONTROL_2], vec![0x47]),
+            Transaction::write(DEVICE_ADDRESS, vec![REG_CONTROL_2, 0x87]),
+        ];
+        let waited = run(&expectations, |rtc| {
+            rtc.init_device().unwrap();
+            rtc.delay.ns
+        });
+        assert_eq!(waited, 0);
+    }
+
+    #[test]
+    fn init_waits_for_the_oscillator() {
+        let read = |value| Transaction::write_read(DEVICE_ADDRESS, vec![REG_SECONDS], vec![value]);
+        let clear = Transaction::write(DEVICE_ADDRESS, vec![REG_SECONDS, 0x00]);
+        let expectations = [
+            read(0x80),
+            Transaction::write(DEVICE_ADDRESS, vec![REG_CONTROL_1, 0x58]),
+            read(0x80),
+            clear.clone(),
+            // Still stopped after the first wait.
+            read(0x80),
+            clear,
+            read(0x00),
+            Transaction::write_read(DEVICE_ADDRESS, vec![REG_CONTROL_2], vec![0x00]),
+            Transaction::write(DEVICE_ADDRESS, vec![REG_CONTROL_2, 0x80]),
+        ];
+        let waited = run(&expectations, |rtc| {
+            rtc.init_device().unwrap();
+            rtc.delay.ns
+        });
+        assert_eq!(waited, 30_000_000);
+    }
+
+    #[test]
+    fn init_gives_up_on_the_oscillator() {
+        let mut expectations = vec![
+            Transaction::write_read(DEVICE_ADDRESS, vec![REG_SECONDS], vec![0x80]),
+            Transaction::write(DEVICE_ADDRESS, vec![REG_CONTROL_1, 0x58]),
+        ];
+        let mut waited_ms = 0;
+        let mut wait_ms = OSCILLATOR_POLL_MS;
+        while waited_ms < OSCILLATOR_TIMEOUT_MS {
+            expectations.push(Transaction::write_read(
+                DEVICE_ADDRESS,
+                vec![REG_SECONDS],
+                vec![0x80],
+            ));
+            expectations.push(Transaction::write(DEVICE_ADDRESS, vec![REG_SECONDS, 0x00]));
+            waited_ms += wait_ms;
+            wait_ms = (wait_ms * 2).min(OSCILLATOR_MAX_POLL_MS);
+        }
+        expectations.push(Transaction::write_read(
+            DEVICE_ADDRESS,
+            vec![REG_SECONDS],
+            vec![0x80],
+        ));
+        expectations.push(Transaction::write_read(
+            DEVICE_ADDRESS,
+            vec![REG_CONTROL_2],
+            vec![0x00],
+        ));
+        expectations.push(Transaction::write(
+            DEVICE_ADDRESS,
+            vec![REG_CONTROL_2, 0x80],
+        ));
+        let waited = run(&expectations, |rtc| {
+            rtc.init_device().unwrap();
+            rtc.delay.ns
+        });
+        assert_eq!(waited, waited_ms as u64 * 1_000_000);
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        let write = Transaction::write(DEVICE_ADDRESS, vec![REG_CONTROL_2, 0x80]);
+        let expectations = [
+            write.clone().with_error(NACK),
+            write.clone().with_error(ErrorKind::Bus),
+            write,
+        ];
+        let waited = run(&expectations, |rtc| {
+            rtc.write_register(REG_CONTROL_2, 0x80).unwrap();
+            rtc.delay.ns
+        });
+        assert_eq!(waited, 2 * RETRY_DELAY_MS as u64 * 1_000_000);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let write = Transaction::write(DEVICE_ADDRESS, vec![REG_CONTROL_2, 0x80]);
+        let expectations = vec![write.with_error(NACK); MAX_ATTEMPTS];
+        let result = run(&expectations, |rtc| rtc.write_register(REG_CONTROL_2, 0x80));
+        assert!(matches!(result, Err(Error::I2C(NACK))));
+    }
+
+    #[test]
+    fn does_not_retry_other_errors() {
+        let write = Transaction::write(DEVICE_ADDRESS, vec![REG_CONTROL_2, 0x80]);
+        let expectations = [write.with_error(ErrorKind::Overrun)];
+        let result = run(&expectations, |rtc| rtc.write_register(REG_CONTROL_2, 0x80));
+        assert!(matches!(result, Err(Error::I2C(ErrorKind::Overrun))));
+    }
+}
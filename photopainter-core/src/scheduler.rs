@@ -0,0 +1,448 @@
+// When to refresh, and what to show.
+//
+// The frame wakes up at a few fixed times of day, each with its own display mode: say the calendar
+// in the morning, photos at lunchtime and art in the evening. The schedule is kept as text in
+// SCHEDULE_FILE on the SD card, one entry per line:
+//
+//     00:00 calendar
+//     06:00 calendar
+//     12:00 slideshow
+//     18:00 art
+//
+// Blank lines and lines starting with '#' are ignored. Without the file, `Schedule::default()`
+// is used. It starts with a refresh at midnight, so that the calendar never shows yesterday's
+// date. Before powering off, the RTC alarm is set for the first entry after the current time.
+
+use core::fmt;
+
+use crate::datetime::DateTime;
+
+/// File in the root directory of the SD card holding the schedule.
+pub const SCHEDULE_FILE: &str = "SCHEDULE.TXT";
+
+/// Most entries a schedule can have.
+pub const MAX_ENTRIES: usize = 24;
+
+/// What to draw at a refresh.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DisplayMode {
+    Calendar,
+    Slideshow,
+    Art,
+}
+
+impl DisplayMode {
+    pub const ALL: [DisplayMode; 3] = [
+        DisplayMode::Calendar,
+        DisplayMode::Slideshow,
+        DisplayMode::Art,
+    ];
+
+    /// The name used in the schedule file.
+    pub fn name(self) -> &'static str {
+        match self {
+            DisplayMode::Calendar => "calendar",
+            DisplayMode::Slideshow => "slideshow",
+            DisplayMode::Art => "art",
+        }
+    }
+
+    /// Looks up a mode by name, ignoring case.
+    pub fn find(name: &str) -> Option<DisplayMode> {
+        DisplayMode::ALL
+            .into_iter()
+            .find(|mode| mode.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Turns the mode into a byte, for the settings record in flash.
+    pub fn to_bits(self) -> u8 {
+        self as u8
+    }
+
+    /// Inverse of `to_bits`, or `None` if `bits` isn't a mode.
+    pub fn from_bits(bits: u8) -> Option<DisplayMode> {
+        DisplayMode::ALL.get(bits as usize).copied()
+    }
+}
+
+impl fmt::Display for DisplayMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A time of day, to the minute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TimeOfDay {
+    pub hours: u8,
+    pub minutes: u8,
+}
+
+impl TimeOfDay {
+    /// Parses a time such as `06:00`.
+    pub fn parse(text: &str) -> Option<TimeOfDay> {
+        let (hours, minutes) = text.split_once(':')?;
+        let time = TimeOfDay {
+            hours: parse_two_digits(hours)?,
+            minutes: parse_two_digits(minutes)?,
+        };
+        (time.hours < 24 && time.minutes < 60).then_some(time)
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.hours, self.minutes)
+    }
+}
+
+/// A refresh at a time of day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Entry {
+    pub hours: u8,
+    pub minutes: u8,
+    pub mode: DisplayMode,
+}
+
+impl Entry {
+    fn seconds_of_day(&self) -> u32 {
+        self.hours as u32 * 3600 + self.minutes as u32 * 60
+    }
+
+    /// Parses an entry such as `06:00 calendar`.
+    fn parse(line: &str) -> Option<Entry> {
+        let mut words = line.split_whitespace();
+        let time = TimeOfDay::parse(words.next()?)?;
+        let mode = DisplayMode::find(words.next()?)?;
+        words.next().is_none().then_some(Entry {
+            hours: time.hours,
+            minutes: time.minutes,
+            mode,
+        })
+    }
+}
+
+fn parse_two_digits(text: &str) -> Option<u8> {
+    if text.len() != 2 || !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
+
+/// Why a schedule file can't be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ScheduleError {
+    /// The line with this number (counting from 1) isn't an entry, or repeats an earlier time.
+    BadLine(usize),
+    /// More than `MAX_ENTRIES` entries.
+    TooManyEntries,
+    /// No entries at all.
+    Empty,
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::BadLine(line) => write!(f, "bad entry on line {}", line),
+            ScheduleError::TooManyEntries => {
+                write!(f, "more than {} entries", MAX_ENTRIES)
+            }
+            ScheduleError::Empty => f.write_str("no entries"),
+        }
+    }
+}
+
+/// The refreshes for each day, in time order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    /// Never empty.
+    entries: heapless::Vec<Entry, MAX_ENTRIES>,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        let entry = |hours, mode| Entry {
+            hours,
+            minutes: 0,
+            mode,
+        };
+        Schedule {
+            entries: heapless::Vec::from_slice(&[
+                entry(0, DisplayMode::Calendar),
+                entry(6, DisplayMode::Calendar),
+                entry(12, DisplayMode::Slideshow),
+                entry(18, DisplayMode::Art),
+            ])
+            .unwrap(),
+        }
+    }
+}
+
+impl Schedule {
+    /// Parses the contents of `SCHEDULE_FILE`. The entries can be in any order.
+    pub fn parse(contents: &[u8]) -> Result<Schedule, ScheduleError> {
+        let contents = core::str::from_utf8(contents).map_err(|e| {
+            let line = contents[..e.valid_up_to()]
+                .iter()
+                .filter(|&&b| b == b'\n')
+                .count();
+            ScheduleError::BadLine(line + 1)
+        })?;
+        let mut entries = heapless::Vec::<Entry, MAX_ENTRIES>::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = Entry::parse(line)
+                .filter(|entry| {
+                    !entries
+                        .iter()
+                        .any(|other| other.seconds_of_day() == entry.seconds_of_day())
+                })
+                .ok_or(ScheduleError::BadLine(i + 1))?;
+            entries
+                .push(entry)
+                .map_err(|_| ScheduleError::TooManyEntries)?;
+        }
+        if entries.is_empty() {
+            return Err(ScheduleError::Empty);
+        }
+        entries.sort_unstable_by_key(Entry::seconds_of_day);
+        Ok(Schedule { entries })
+    }
+
+    /// A schedule with one refresh a day.
+    pub fn daily(entry: Entry) -> Schedule {
+        Schedule {
+            entries: heapless::Vec::from_slice(&[entry]).unwrap(),
+        }
+    }
+
+    /// Returns a copy with every entry showing `mode`.
+    pub fn with_mode(&self, mode: DisplayMode) -> Schedule {
+        let mut schedule = self.clone();
+        for entry in &mut schedule.entries {
+            entry.mode = mode;
+        }
+        schedule
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Returns the first entry after `now`, and when it is due, or `None` if that is beyond what
+    /// the RTC can hold.
+    pub fn next(&self, now: &DateTime) -> Option<(DateTime, Entry)> {
+        let now_seconds = now.hours as u32 * 3600 + now.minutes as u32 * 60 + now.seconds as u32;
+        let (entry, midnight) = match self
+            .entries
+            .iter()
+            .find(|entry| entry.seconds_of_day() > now_seconds)
+        {
+            Some(entry) => (
+                entry,
+                DateTime {
+                    hours: 0,
+                    minutes: 0,
+                    seconds: 0,
+                    ..*now
+                },
+            ),
+            // Nothing left today, so the first one tomorrow.
+            None => (&self.entries[0], now.next_midnight()?),
+        };
+        let at = midnight.checked_add_seconds(entry.seconds_of_day())?;
+        Some((at, *entry))
+    }
+
+    /// The mode of the entry most recently due at `now`. Before the first entry of the day, that
+    /// is the last one of the day before.
+    pub fn current(&self, now: &DateTime) -> DisplayMode {
+        // Entries are on the minute, so the seconds don't matter.
+        self.mode_at(TimeOfDay {
+            hours: now.hours,
+            minutes: now.minutes,
+        })
+    }
+
+    /// Like `current`, for a time of day.
+    pub fn mode_at(&self, time: TimeOfDay) -> DisplayMode {
+        let seconds = time.hours as u32 * 3600 + time.minutes as u32 * 60;
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| entry.seconds_of_day() <= seconds)
+            .unwrap_or(&self.entries[self.entries.len() - 1])
+            .mode
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    fn at(day: u8, hours: u8, minutes: u8, seconds: u8) -> DateTime {
+        DateTime {
+            year: 2024,
+            month: 12,
+            day,
+            hours,
+            minutes,
+            seconds,
+        }
+    }
+
+    #[test]
+    fn parses_schedules() {
+        let schedule = Schedule::parse(
+            b"# Evening first, to check sorting\r\n18:30 ART\n\n  06:00   calendar\n12:00 slideshow\n",
+        )
+        .unwrap();
+        assert_eq!(
+            schedule.entries(),
+            &[
+                Entry {
+                    hours: 6,
+                    minutes: 0,
+                    mode: DisplayMode::Calendar
+                },
+                Entry {
+                    hours: 12,
+                    minutes: 0,
+                    mode: DisplayMode::Slideshow
+                },
+                Entry {
+                    hours: 18,
+                    minutes: 30,
+                    mode: DisplayMode::Art
+                },
+            ]
+        );
+        assert_eq!(
+            Schedule::parse(b"00:00 calendar\n06:00 calendar\n12:00 slideshow\n18:00 art\n"),
+            Ok(Schedule::default())
+        );
+    }
+
+    #[test]
+    fn rejects_bad_schedules() {
+        for (contents, error) in [
+            (&b""[..], ScheduleError::Empty),
+            (b"# nothing\n\n", ScheduleError::Empty),
+            (b"6:00 art", ScheduleError::BadLine(1)),
+            (b"06:00\n", ScheduleError::BadLine(1)),
+            (b"06:00 art\n24:00 art", ScheduleError::BadLine(2)),
+            (b"06:60 art", ScheduleError::BadLine(1)),
+            (b"06:00 photos", ScheduleError::BadLine(1)),
+            (b"06:00 art now", ScheduleError::BadLine(1)),
+            (b"06:00 art\n06:00 calendar", ScheduleError::BadLine(2)),
+            (b"06:00 art\n\xFF", ScheduleError::BadLine(2)),
+        ] {
+            assert_eq!(Schedule::parse(contents), Err(error));
+        }
+
+        let mut contents = heapless::Vec::<u8, 512>::new();
+        for hours in 0..=MAX_ENTRIES {
+            write_entry(&mut contents, hours);
+        }
+        assert_eq!(
+            Schedule::parse(&contents),
+            Err(ScheduleError::TooManyEntries)
+        );
+    }
+
+    fn write_entry(contents: &mut heapless::Vec<u8, 512>, index: usize) {
+        use core::fmt::Write;
+        let mut line = heapless::String::<16>::new();
+        writeln!(line, "{:02}:{:02} art", index / 2, index % 2 * 30).unwrap();
+        contents.extend_from_slice(line.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn times_of_day() {
+        let time = TimeOfDay::parse("07:05").unwrap();
+        assert_eq!(
+            time,
+            TimeOfDay {
+                hours: 7,
+                minutes: 5
+            }
+        );
+        assert_eq!(std::format!("{}", time), "07:05");
+        for bad in ["7:05", "07:5", "24:00", "12:60", "0705", "07:05:00"] {
+            assert_eq!(TimeOfDay::parse(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn next_entry() {
+        let schedule = Schedule::default();
+        let next = |now| schedule.next(&now).map(|(at, entry)| (at, entry.mode));
+        assert_eq!(
+            next(at(30, 5, 59, 59)),
+            Some((at(30, 6, 0, 0), DisplayMode::Calendar))
+        );
+        // Just woken up by the alarm.
+        assert_eq!(
+            next(at(30, 6, 0, 0)),
+            Some((at(30, 12, 0, 0), DisplayMode::Slideshow))
+        );
+        assert_eq!(
+            next(at(30, 12, 0, 5)),
+            Some((at(30, 18, 0, 0), DisplayMode::Art))
+        );
+        // Into the next day, month and year.
+        assert_eq!(
+            next(at(30, 18, 0, 0)),
+            Some((at(31, 0, 0, 0), DisplayMode::Calendar))
+        );
+        assert_eq!(
+            next(at(31, 23, 59, 0)),
+            Some((
+                DateTime {
+                    year: 2025,
+                    month: 1,
+                    day: 1,
+                    hours: 0,
+                    minutes: 0,
+                    seconds: 0,
+                },
+                DisplayMode::Calendar
+            ))
+        );
+        let last_day = DateTime {
+            year: 2099,
+            ..at(31, 19, 0, 0)
+        };
+        assert_eq!(schedule.next(&last_day), None);
+    }
+
+    #[test]
+    fn current_mode() {
+        let schedule = Schedule::default();
+        assert_eq!(schedule.current(&at(30, 6, 0, 3)), DisplayMode::Calendar);
+        assert_eq!(
+            schedule.current(&at(30, 17, 59, 59)),
+            DisplayMode::Slideshow
+        );
+        assert_eq!(schedule.current(&at(30, 23, 0, 0)), DisplayMode::Art);
+        assert_eq!(schedule.current(&at(30, 2, 0, 0)), DisplayMode::Calendar);
+
+        let once = Schedule::parse(b"07:15 slideshow").unwrap();
+        assert_eq!(once.current(&at(30, 0, 0, 0)), DisplayMode::Slideshow);
+        let evening = Schedule::parse(b"07:00 calendar\n20:00 art").unwrap();
+        assert_eq!(evening.current(&at(30, 3, 0, 0)), DisplayMode::Art);
+        assert_eq!(
+            once.next(&at(30, 8, 0, 0)).map(|(at, _)| at),
+            Some(at(31, 7, 15, 0))
+        );
+    }
+}
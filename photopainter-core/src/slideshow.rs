@@ -0,0 +1,85 @@
+// Picking the next slideshow image.
+//
+// Images are either pre-converted frame buffers or 24-bit BMP files, in the IMAGES directory of
+// the SD card. They are shown in directory order. The index of the last one shown is saved next
+// to them as text, so that the slideshow carries on where it left off after the power has been
+// cut.
+
+/// Directory on the SD card holding the images.
+pub const IMAGES_DIR: &str = "IMAGES";
+
+/// File in `IMAGES_DIR` holding the index of the image shown last.
+pub const INDEX_FILE: &str = "INDEX.TXT";
+
+/// Returns true if `name` looks like a frame buffer file or a BMP file.
+pub fn is_image(name: &str) -> bool {
+    has_extension(name, "BIN") || is_bmp(name)
+}
+
+/// Returns true if `name` looks like a BMP file, which has to be converted for the panel.
+pub fn is_bmp(name: &str) -> bool {
+    has_extension(name, "BMP")
+}
+
+fn has_extension(name: &str, extension: &str) -> bool {
+    name.rsplit_once('.')
+        .is_some_and(|(_, actual)| actual.eq_ignore_ascii_case(extension))
+}
+
+/// Parses the contents of `INDEX_FILE`.
+pub fn parse_index(contents: &[u8]) -> Option<usize> {
+    core::str::from_utf8(contents).ok()?.trim().parse().ok()
+}
+
+/// Chooses the image to show after the one at `last`, out of `count`. Starts again from the
+/// first image after the last one, or if the saved index is missing or out of range (e.g. because
+/// images were deleted).
+pub fn next_index(last: Option<usize>, count: usize) -> Option<usize> {
+    if count == 0 {
+        return None;
+    }
+    Some(
+        last.and_then(|last| last.checked_add(1))
+            .filter(|&next| next < count)
+            .unwrap_or(0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images() {
+        assert!(is_image("PHOTO1.BIN"));
+        assert!(is_image("photo1.bin"));
+        assert!(!is_image(INDEX_FILE));
+        assert!(!is_image("BIN"));
+        assert!(is_image("PHOTO1.BMP"));
+        assert!(is_bmp("photo1.bmp"));
+        assert!(!is_bmp("PHOTO1.BIN"));
+        assert!(!is_image("PHOTO1.JPG"));
+    }
+
+    #[test]
+    fn indexes() {
+        assert_eq!(parse_index(b"3\r\n"), Some(3));
+        assert_eq!(parse_index(b""), None);
+        assert_eq!(parse_index(b"-1"), None);
+        assert_eq!(parse_index(b"\xff"), None);
+    }
+
+    #[test]
+    fn cycles_through_the_images() {
+        assert_eq!(next_index(None, 3), Some(0));
+        assert_eq!(next_index(Some(0), 3), Some(1));
+        assert_eq!(next_index(Some(1), 3), Some(2));
+        assert_eq!(next_index(Some(2), 3), Some(0));
+        // Images were deleted since.
+        assert_eq!(next_index(Some(7), 3), Some(0));
+        assert_eq!(next_index(Some(0), 0), None);
+        // A damaged or hand-edited index file.
+        assert_eq!(next_index(Some(usize::MAX), 3), Some(0));
+        assert_eq!(next_index(Some(usize::MAX), usize::MAX), Some(0));
+    }
+}
@@ -0,0 +1,339 @@
+// Crash-safe writes to flash.
+//
+// The battery can give out at any moment, including half way through erasing or programming
+// flash, and whatever was being written then must not take the previous copy with it. So
+// everything saved in flash goes through `atomic_write`, which keeps two copies in two sectors
+// ("slots") and only ever erases the older one.
+//
+// In a slot the data comes first, and a header with a sequence number, the length and a CRC-32
+// goes in the last page, written only once the data is in place. Until the header is there, the
+// slot doesn't count; if the data is damaged, the CRC doesn't match and the slot doesn't count
+// either. Reading takes the valid slot with the higher sequence number.
+
+/// Flash is erased a sector at a time...
+pub const SECTOR_SIZE: usize = 4096;
+
+/// ...and programmed a page at a time.
+pub const PAGE_SIZE: usize = 256;
+
+/// The most data a slot can hold: the last page is for the header.
+pub const MAX_DATA_LEN: usize = SECTOR_SIZE - PAGE_SIZE;
+
+const HEADER_OFFSET: u32 = MAX_DATA_LEN as u32;
+const HEADER_LEN: usize = 16;
+const MAGIC: [u8; 4] = *b"PPS1";
+
+/// Erased flash reads as all ones.
+const ERASED: u8 = 0xFF;
+
+/// Flash that can be erased and programmed. Offsets are from the start of the flash.
+pub trait Flash {
+    fn read(&mut self, offset: u32, buffer: &mut [u8]);
+    /// Sets the sector at `offset` to all ones. `offset` is a multiple of `SECTOR_SIZE`.
+    fn erase_sector(&mut self, offset: u32);
+    /// Programs the page at `offset`, which is a multiple of `PAGE_SIZE`. Programming can only
+    /// clear bits.
+    fn program_page(&mut self, offset: u32, data: &[u8; PAGE_SIZE]);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StorageError {
+    /// More data than fits in a slot.
+    TooLong,
+    /// The data didn't read back the way it was written, e.g. because the flash is worn out.
+    VerifyFailed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    sequence: u32,
+    len: usize,
+    crc: u32,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0; HEADER_LEN];
+        header[0..4].copy_from_slice(&MAGIC);
+        header[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        header[8..12].copy_from_slice(&(self.len as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&self.crc.to_le_bytes());
+        header
+    }
+
+    fn decode(header: &[u8; HEADER_LEN]) -> Option<Header> {
+        let word =
+            |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+        let len = word(8) as usize;
+        (header[0..4] == MAGIC && len <= MAX_DATA_LEN).then_some(Header {
+            sequence: word(4),
+            len,
+            crc: word(12),
+        })
+    }
+
+    /// Returns true if this copy is newer than `other`. Sequence numbers wrap around.
+    fn is_newer_than(&self, other: &Header) -> bool {
+        (self.sequence.wrapping_sub(other.sequence) as i32) > 0
+    }
+}
+
+/// Reads the header of the slot at `offset`, if the slot holds a complete, undamaged copy.
+fn valid_header(flash: &mut impl Flash, offset: u32) -> Option<Header> {
+    let mut header = [0; HEADER_LEN];
+    flash.read(offset + HEADER_OFFSET, &mut header);
+    let header = Header::decode(&header)?;
+    let mut crc = Crc32::new();
+    let mut chunk = [0; 64];
+    let mut done = 0;
+    while done < header.len {
+        let len = chunk.len().min(header.len - done);
+        flash.read(offset + done as u32, &mut chunk[..len]);
+        crc.update(&chunk[..len]);
+        done += len;
+    }
+    (crc.finish() == header.crc).then_some(header)
+}
+
+/// Returns the index in `slots` of the newest valid copy, and its header.
+fn newest(flash: &mut impl Flash, slots: [u32; 2]) -> Option<(usize, Header)> {
+    match (valid_header(flash, slots[0]), valid_header(flash, slots[1])) {
+        (Some(a), Some(b)) if b.is_newer_than(&a) => Some((1, b)),
+        (Some(a), _) => Some((0, a)),
+        (None, Some(b)) => Some((1, b)),
+        (None, None) => None,
+    }
+}
+
+/// Reads the newest copy saved in `slots` into `buffer`, and returns it. Returns `None` if
+/// nothing valid has been saved, or if it doesn't fit in `buffer`.
+pub fn read<'a>(flash: &mut impl Flash, slots: [u32; 2], buffer: &'a mut [u8]) -> Option<&'a [u8]> {
+    let (slot, header) = newest(flash, slots)?;
+    let data = buffer.get_mut(..header.len)?;
+    flash.read(slots[slot], data);
+    Some(data)
+}
+
+/// Saves `data` in whichever of `slots` doesn't hold the newest copy, so that the newest copy
+/// survives if the power fails part way through.
+///
+/// `slots` are the offsets of two sectors set aside for this.
+pub fn atomic_write(
+    flash: &mut impl Flash,
+    slots: [u32; 2],
+    data: &[u8],
+) -> Result<(), StorageError> {
+    if data.len() > MAX_DATA_LEN {
+        return Err(StorageError::TooLong);
+    }
+    let (target, sequence) = match newest(flash, slots) {
+        Some((slot, header)) => (slots[1 - slot], header.sequence.wrapping_add(1)),
+        None => (slots[0], 0),
+    };
+
+    flash.erase_sector(target);
+    let mut page = [ERASED; PAGE_SIZE];
+    for (i, chunk) in data.chunks(PAGE_SIZE).enumerate() {
+        page.fill(ERASED);
+        page[..chunk.len()].copy_from_slice(chunk);
+        flash.program_page(target + (i * PAGE_SIZE) as u32, &page);
+    }
+    // The header goes last: it is what makes the copy count.
+    let mut crc = Crc32::new();
+    crc.update(data);
+    let header = Header {
+        sequence,
+        len: data.len(),
+        crc: crc.finish(),
+    };
+    page.fill(ERASED);
+    page[..HEADER_LEN].copy_from_slice(&header.encode());
+    flash.program_page(target + HEADER_OFFSET, &page);
+
+    if valid_header(flash, target) == Some(header) {
+        Ok(())
+    } else {
+        Err(StorageError::VerifyFailed)
+    }
+}
+
+/// CRC-32 as used by zlib and Ethernet.
+struct Crc32(u32);
+
+impl Crc32 {
+    fn new() -> Self {
+        Crc32(!0)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 ^= byte as u32;
+            for _ in 0..8 {
+                self.0 = if self.0 & 1 != 0 {
+                    self.0 >> 1 ^ 0xEDB8_8320
+                } else {
+                    self.0 >> 1
+                };
+            }
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    const SLOTS: [u32; 2] = [0, SECTOR_SIZE as u32];
+
+    /// NOR flash in RAM, which can be made to lose power after a number of operations.
+    #[derive(Clone)]
+    struct FakeFlash {
+        bytes: Vec<u8>,
+        /// Operations left before the power fails; after that, nothing changes.
+        operations_left: Option<usize>,
+    }
+
+    impl FakeFlash {
+        fn new() -> Self {
+            FakeFlash {
+                bytes: std::vec![ERASED; 2 * SECTOR_SIZE],
+                operations_left: None,
+            }
+        }
+
+        fn powered(&mut self) -> bool {
+            match &mut self.operations_left {
+                None => true,
+                Some(0) => false,
+                Some(left) => {
+                    *left -= 1;
+                    true
+                }
+            }
+        }
+    }
+
+    impl Flash for FakeFlash {
+        fn read(&mut self, offset: u32, buffer: &mut [u8]) {
+            let offset = offset as usize;
+            buffer.copy_from_slice(&self.bytes[offset..offset + buffer.len()]);
+        }
+
+        fn erase_sector(&mut self, offset: u32) {
+            assert_eq!(offset as usize % SECTOR_SIZE, 0);
+            if self.powered() {
+                let offset = offset as usize;
+                self.bytes[offset..offset + SECTOR_SIZE].fill(ERASED);
+            }
+        }
+
+        fn program_page(&mut self, offset: u32, data: &[u8; PAGE_SIZE]) {
+            assert_eq!(offset as usize % PAGE_SIZE, 0);
+            if self.powered() {
+                let offset = offset as usize;
+                for (byte, &new) in self.bytes[offset..offset + PAGE_SIZE].iter_mut().zip(data) {
+                    *byte &= new;
+                }
+            }
+        }
+    }
+
+    fn read_vec(flash: &mut FakeFlash) -> Option<Vec<u8>> {
+        let mut buffer = [0; MAX_DATA_LEN];
+        read(flash, SLOTS, &mut buffer).map(<[u8]>::to_vec)
+    }
+
+    #[test]
+    fn crc() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn alternates_between_slots() {
+        let mut flash = FakeFlash::new();
+        assert_eq!(read_vec(&mut flash), None);
+        for i in 0..5u8 {
+            let data = std::vec![i; 100 + i as usize * 300];
+            atomic_write(&mut flash, SLOTS, &data).unwrap();
+            assert_eq!(read_vec(&mut flash), Some(data));
+            assert_eq!(newest(&mut flash, SLOTS).unwrap().0, i as usize % 2);
+        }
+        atomic_write(&mut flash, SLOTS, &[]).unwrap();
+        assert_eq!(read_vec(&mut flash), Some(Vec::new()));
+    }
+
+    #[test]
+    fn survives_power_cuts() {
+        let mut flash = FakeFlash::new();
+        atomic_write(&mut flash, SLOTS, b"first").unwrap();
+        atomic_write(&mut flash, SLOTS, b"second").unwrap();
+        let old = std::vec![0x5A; 1000];
+        atomic_write(&mut flash, SLOTS, &old).unwrap();
+
+        // An erase, four data pages and the header.
+        let new = std::vec![0xA5; 1000];
+        for operations in 0..6 {
+            let mut cut = flash.clone();
+            cut.operations_left = Some(operations);
+            assert_eq!(
+                atomic_write(&mut cut, SLOTS, &new),
+                Err(StorageError::VerifyFailed)
+            );
+            assert_eq!(read_vec(&mut cut), Some(old.clone()), "{}", operations);
+        }
+        let mut cut = flash.clone();
+        cut.operations_left = Some(6);
+        atomic_write(&mut cut, SLOTS, &new).unwrap();
+        assert_eq!(read_vec(&mut cut), Some(new));
+    }
+
+    #[test]
+    fn ignores_damaged_slots() {
+        let mut flash = FakeFlash::new();
+        atomic_write(&mut flash, SLOTS, b"old").unwrap();
+        atomic_write(&mut flash, SLOTS, b"new").unwrap();
+        // A bit flipped in the newer copy.
+        flash.bytes[SECTOR_SIZE] ^= 0x01;
+        assert_eq!(read_vec(&mut flash), Some(b"old".to_vec()));
+        // The next write replaces the damaged copy, not the good one.
+        atomic_write(&mut flash, SLOTS, b"newer").unwrap();
+        assert_eq!(read_vec(&mut flash), Some(b"newer".to_vec()));
+        assert_eq!(&flash.bytes[..3], b"old");
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let header = |sequence| Header {
+            sequence,
+            len: 0,
+            crc: 0,
+        };
+        assert!(header(0).is_newer_than(&header(u32::MAX)));
+        assert!(!header(u32::MAX).is_newer_than(&header(0)));
+        assert!(header(2).is_newer_than(&header(1)));
+        assert!(!header(1).is_newer_than(&header(1)));
+    }
+
+    #[test]
+    fn rejects_oversized_data() {
+        let mut flash = FakeFlash::new();
+        let data = [0; MAX_DATA_LEN + 1];
+        assert_eq!(
+            atomic_write(&mut flash, SLOTS, &data),
+            Err(StorageError::TooLong)
+        );
+        atomic_write(&mut flash, SLOTS, &data[..MAX_DATA_LEN]).unwrap();
+        let mut small = [0; 10];
+        assert_eq!(read(&mut flash, SLOTS, &mut small), None);
+    }
+}
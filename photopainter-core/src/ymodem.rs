@@ -0,0 +1,360 @@
+// Receiving files with YMODEM, or XMODEM-1K.
+//
+// Terminal emulators can send files this way without any extra software, which makes it the
+// easiest way to get an image onto the frame over the serial console. Only the receiving side,
+// with CRC-16 checks, is implemented. The protocol is driven one byte at a time: the firmware feeds
+// in what arrives and sends back whatever `Receiver` asks for.
+//
+// YMODEM starts with a block 0 holding the file name and size, which lets the padding at the end
+// of the last block be dropped. XMODEM-1K starts directly with block 1, and the padding is kept.
+
+/// Start of a 128 byte block.
+pub const SOH: u8 = 0x01;
+/// Start of a 1024 byte block.
+pub const STX: u8 = 0x02;
+/// End of the file.
+pub const EOT: u8 = 0x04;
+pub const ACK: u8 = 0x06;
+pub const NAK: u8 = 0x15;
+/// Cancels the transfer; sent twice.
+pub const CAN: u8 = 0x18;
+/// Asks the sender to start, using CRC-16 rather than checksums.
+pub const CRC_MODE: u8 = b'C';
+
+const HEADER_LENGTH: usize = 3;
+const CRC_LENGTH: usize = 2;
+const MAX_PACKET_LENGTH: usize = HEADER_LENGTH + 1024 + CRC_LENGTH;
+
+/// What to do after a byte has been received.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Response<'a> {
+    /// File data arrived. Store it, then reply with `ACK`.
+    Data(&'a [u8]),
+    /// Send these bytes back.
+    Reply(&'static [u8]),
+    /// The file is complete. Reply with `ACK`.
+    Finished,
+    /// The transfer failed, or the sender cancelled it. Reply with `CAN` twice.
+    Aborted,
+}
+
+/// The receiving end of a transfer.
+#[derive(Debug)]
+pub struct Receiver {
+    packet: [u8; MAX_PACKET_LENGTH],
+    len: usize,
+    /// The block number expected next.
+    block: u8,
+    /// Whether a YMODEM header (block 0) may come next.
+    header_expected: bool,
+    started: bool,
+    /// Bytes of the file still to come, if the sender said how big it is.
+    remaining: Option<u32>,
+    /// Whether the sender has said the file is complete.
+    eot: bool,
+    cancels: u8,
+}
+
+impl Default for Receiver {
+    fn default() -> Self {
+        Receiver {
+            packet: [0; MAX_PACKET_LENGTH],
+            len: 0,
+            block: 0,
+            header_expected: true,
+            started: false,
+            remaining: None,
+            eot: false,
+            cancels: 0,
+        }
+    }
+}
+
+impl Receiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns true once the sender has started sending.
+    pub fn started(&self) -> bool {
+        self.started
+    }
+
+    /// Returns what to send when nothing has arrived for a while: a renewed request to start,
+    /// or a NAK to have the current block resent.
+    pub fn timed_out(&mut self) -> &'static [u8] {
+        self.len = 0;
+        if self.started {
+            &[NAK]
+        } else {
+            &[CRC_MODE]
+        }
+    }
+
+    /// Processes one received byte.
+    pub fn push(&mut self, byte: u8) -> Option<Response<'_>> {
+        if self.len == 0 {
+            if byte == CAN {
+                self.cancels += 1;
+                return (self.cancels >= 2).then_some(Response::Aborted);
+            }
+            self.cancels = 0;
+            return match byte {
+                SOH | STX => {
+                    self.started = true;
+                    self.packet[0] = byte;
+                    self.len = 1;
+                    None
+                }
+                EOT => Some(self.end_of_file()),
+                // Line noise, or the tail of something we already gave up on.
+                _ => None,
+            };
+        }
+
+        self.packet[self.len] = byte;
+        self.len += 1;
+        let data_length = if self.packet[0] == STX { 1024 } else { 128 };
+        if self.len < HEADER_LENGTH + data_length + CRC_LENGTH {
+            return None;
+        }
+        self.len = 0;
+        Some(self.block_received(data_length))
+    }
+
+    fn end_of_file(&mut self) -> Response<'_> {
+        self.started = true;
+        if !self.eot {
+            // Have the sender confirm that this isn't a corrupted byte.
+            self.eot = true;
+            return Response::Reply(&[NAK]);
+        }
+        if self.remaining.is_some() {
+            // YMODEM follows up with an empty header to end the batch.
+            self.header_expected = true;
+            Response::Reply(&[ACK, CRC_MODE])
+        } else {
+            Response::Finished
+        }
+    }
+
+    fn block_received(&mut self, data_length: usize) -> Response<'_> {
+        let (number, inverse) = (self.packet[1], self.packet[2]);
+        let (data, crc) = self.packet[HEADER_LENGTH..].split_at(data_length);
+        if number != !inverse || crc16(data) != u16::from_be_bytes([crc[0], crc[1]]) {
+            return Response::Reply(&[NAK]);
+        }
+
+        if number == 0 && self.header_expected {
+            return self.header_received();
+        }
+        if number == 1 && self.header_expected && !self.eot {
+            // No header, so this is XMODEM.
+            self.header_expected = false;
+            self.block = 1;
+        }
+        if number == self.block.wrapping_sub(1) {
+            // Our ACK got lost, and the sender repeated the block.
+            return Response::Reply(&[ACK]);
+        }
+        if number != self.block || self.eot {
+            return Response::Aborted;
+        }
+        self.block = self.block.wrapping_add(1);
+
+        let mut data = &self.packet[HEADER_LENGTH..HEADER_LENGTH + data_length];
+        if let Some(remaining) = &mut self.remaining {
+            let len = data.len().min(*remaining as usize);
+            data = &data[..len];
+            *remaining -= len as u32;
+        }
+        Response::Data(data)
+    }
+
+    fn header_received(&mut self) -> Response<'_> {
+        let header = &self.packet[HEADER_LENGTH..HEADER_LENGTH + 128];
+        if header[0] == 0 {
+            // An empty header ends the batch. We only take one file, so it is also the end of
+            // the transfer if nothing was sent.
+            return Response::Finished;
+        }
+        if self.eot {
+            // A second file.
+            return Response::Aborted;
+        }
+        // The name is ignored, since the file was named in the command.
+        let mut fields = header.split(|&b| b == 0).nth(1).unwrap_or_default();
+        if let Some(end) = fields.iter().position(|&b| b == b' ') {
+            fields = &fields[..end];
+        }
+        self.remaining = Some(
+            core::str::from_utf8(fields)
+                .ok()
+                .and_then(|size| size.parse().ok())
+                .unwrap_or(u32::MAX),
+        );
+        self.header_expected = false;
+        self.block = 1;
+        Response::Reply(&[ACK, CRC_MODE])
+    }
+}
+
+/// CRC-16 as used by XMODEM (polynomial 0x1021, starting from zero).
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    fn block(number: u8, data: &[u8]) -> Vec<u8> {
+        let mut padded = data.to_vec();
+        let length = if data.len() > 128 { 1024 } else { 128 };
+        // Headers are padded with zeros, file data with Ctrl-Z.
+        padded.resize(length, if number == 0 { 0 } else { 0x1A });
+        let mut packet = std::vec![if length == 1024 { STX } else { SOH }, number, !number];
+        packet.extend_from_slice(&padded);
+        packet.extend_from_slice(&crc16(&padded).to_be_bytes());
+        packet
+    }
+
+    fn header(name: &str, size: usize) -> Vec<u8> {
+        let mut data = Vec::from(name.as_bytes());
+        data.push(0);
+        data.extend_from_slice(std::format!("{} 14757025521 100644", size).as_bytes());
+        block(0, &data)
+    }
+
+    /// Feeds in `input`, and returns the file contents and how the transfer ended.
+    fn receive(receiver: &mut Receiver, input: &[u8]) -> (Vec<u8>, Option<Response<'static>>) {
+        let mut file = Vec::new();
+        for &byte in input {
+            match receiver.push(byte) {
+                Some(Response::Data(data)) => file.extend_from_slice(data),
+                Some(Response::Reply(_)) | None => {}
+                Some(Response::Finished) => return (file, Some(Response::Finished)),
+                Some(Response::Aborted) => return (file, Some(Response::Aborted)),
+            }
+        }
+        (file, None)
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(crc16(b""), 0);
+    }
+
+    #[test]
+    fn ymodem() {
+        let contents: Vec<u8> = (0..1200u32).map(|i| i as u8).collect();
+        let mut input = header("PHOTO.BIN", contents.len());
+        input.extend(block(1, &contents[..1024]));
+        input.extend(block(2, &contents[1024..]));
+        input.extend([EOT, EOT]);
+        input.extend(block(0, &[]));
+
+        let mut receiver = Receiver::new();
+        assert_eq!(receiver.timed_out(), [CRC_MODE]);
+        let (file, end) = receive(&mut receiver, &input);
+        assert_eq!(file, contents);
+        assert_eq!(end, Some(Response::Finished));
+    }
+
+    #[test]
+    fn block_numbers_wrap_around() {
+        let contents: Vec<u8> = (0..300 * 128u32).map(|i| (i / 128) as u8).collect();
+        let mut input = header("BIG.BIN", contents.len());
+        for (i, data) in contents.chunks(128).enumerate() {
+            input.extend(block((i + 1) as u8, data));
+        }
+        input.extend([EOT, EOT]);
+        input.extend(block(0, &[]));
+        let (file, end) = receive(&mut Receiver::new(), &input);
+        assert_eq!(file, contents);
+        assert_eq!(end, Some(Response::Finished));
+    }
+
+    #[test]
+    fn xmodem() {
+        let mut input = block(1, b"hello");
+        input.extend([EOT, EOT]);
+        let mut receiver = Receiver::new();
+        let (file, end) = receive(&mut receiver, &input);
+        // Without a header there is no way to tell the padding from the data.
+        assert_eq!(&file[..5], b"hello");
+        assert_eq!(file.len(), 128);
+        assert_eq!(end, Some(Response::Finished));
+    }
+
+    #[test]
+    fn replies() {
+        let mut receiver = Receiver::new();
+        let header = header("A.BIN", 3);
+        let (last, rest) = header.split_last().unwrap();
+        assert_eq!(receive(&mut receiver, rest), (Vec::new(), None));
+        assert_eq!(
+            receiver.push(*last),
+            Some(Response::Reply(&[ACK, CRC_MODE]))
+        );
+        assert!(receiver.started());
+
+        // A corrupted block is asked for again.
+        let mut corrupted = block(1, b"abc");
+        corrupted[10] ^= 1;
+        let (last, rest) = corrupted.split_last().unwrap();
+        receive(&mut receiver, rest);
+        assert_eq!(receiver.push(*last), Some(Response::Reply(&[NAK])));
+
+        let good = block(1, b"abc");
+        let (last, rest) = good.split_last().unwrap();
+        receive(&mut receiver, rest);
+        assert_eq!(receiver.push(*last), Some(Response::Data(b"abc")));
+        // So is a block cut short, once the line goes quiet.
+        receive(&mut receiver, &good[..50]);
+        assert_eq!(receiver.timed_out(), [NAK]);
+        // A repeat of a block that was already received is acknowledged but not stored.
+        receive(&mut receiver, rest);
+        assert_eq!(receiver.push(*last), Some(Response::Reply(&[ACK])));
+
+        assert_eq!(receiver.push(EOT), Some(Response::Reply(&[NAK])));
+        assert_eq!(receiver.push(EOT), Some(Response::Reply(&[ACK, CRC_MODE])));
+        assert_eq!(
+            receive(&mut receiver, &block(0, &[])).1,
+            Some(Response::Finished)
+        );
+    }
+
+    #[test]
+    fn aborts() {
+        let mut receiver = Receiver::new();
+        assert_eq!(receiver.push(CAN), None);
+        assert_eq!(receiver.push(CAN), Some(Response::Aborted));
+
+        // A skipped block can't be recovered from.
+        let mut receiver = Receiver::new();
+        let mut input = header("A.BIN", 300);
+        input.extend(block(2, b"abc"));
+        assert_eq!(receive(&mut receiver, &input).1, Some(Response::Aborted));
+
+        // Only one file can be received.
+        let mut receiver = Receiver::new();
+        let mut input = header("A.BIN", 3);
+        input.extend(block(1, b"abc"));
+        input.extend([EOT, EOT]);
+        input.extend(header("B.BIN", 3));
+        assert_eq!(receive(&mut receiver, &input).1, Some(Response::Aborted));
+    }
+}
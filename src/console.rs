@@ -0,0 +1,227 @@
+// A simple line-oriented command console.
+//
+// The console doesn't care what it is running over, as long as it can read bytes without blocking
+// and write both bytes and formatted text. By default, output newlines are expanded to CRLF so
+// that plain terminal emulators display it correctly. Parsing and line editing live in
+// `photopainter_core::console`.
+//
+// Echo, CRLF expansion and color can be turned off with TERM, so that scripts piping commands in
+// get plain, predictable output.
+//
+// Long HELP output pauses at a `--more--` prompt. The pager doesn't block: `HelpPager` keeps the
+// rest of the list, and it is shown a page at a time as keys arrive through `poll`.
+
+use core::fmt::{self, Write};
+use embedded_hal_nb::serial::Read;
+
+pub use photopainter_core::console::{Command, CommandSpec, IdleMonitor, ParseError, TermMode};
+use photopainter_core::console::{Edit, HelpPager, LineEditor, COMMANDS, USAGE_WIDTH};
+
+const MORE_PROMPT: &str = "--more--";
+
+// Width of the label column in `write_field` output.
+const FIELD_LABEL_WIDTH: usize = 18;
+
+const ANSI_BOLD: &str = "\x1b[1m";
+const ANSI_RED: &str = "\x1b[31m";
+const ANSI_GREEN: &str = "\x1b[32m";
+const ANSI_RESET: &str = "\x1b[0m";
+
+pub struct Console<S> {
+    serial: S,
+    editor: LineEditor,
+    mode: TermMode,
+    pager: HelpPager,
+}
+
+impl<S> Console<S>
+where
+    S: Read<u8> + embedded_hal_nb::serial::Write<u8> + fmt::Write,
+{
+    pub fn new(serial: S) -> Self {
+        Console {
+            serial,
+            editor: LineEditor::new(),
+            mode: TermMode::default(),
+            pager: HelpPager::new(),
+        }
+    }
+
+    /// Consumes any pending input, echoing it back.
+    ///
+    /// Returns the parsed command once a complete, non-empty line has been received.
+    pub fn poll(&mut self) -> Option<Result<Command, ParseError>> {
+        while let Ok(byte) = self.serial.read() {
+            if self.pager.is_paused() {
+                self.continue_help(byte);
+                continue;
+            }
+            match self.editor.push(byte) {
+                Some(Edit::Echo(byte)) if self.mode.echo => {
+                    let _ = self.serial.write_char(byte as char);
+                }
+                Some(Edit::Erase) if self.mode.echo => {
+                    let _ = self.serial.write_str("\x08 \x08");
+                }
+                Some(Edit::Line(result)) => {
+                    if self.mode.echo {
+                        let _ = writeln!(self);
+                    }
+                    return Some(result);
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Reads a byte without any line editing, for binary transfers such as UPLOAD.
+    #[cfg(not(feature = "kiosk"))]
+    pub fn read_byte(&mut self) -> Option<u8> {
+        while let Ok(byte) = self.serial.read() {
+            if let Some(byte) = self.editor.raw(byte) {
+                return Some(byte);
+            }
+        }
+        None
+    }
+
+    /// Writes bytes as they are, for binary transfers.
+    #[cfg(not(feature = "kiosk"))]
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let _ = embedded_hal_nb::nb::block!(self.serial.write(byte));
+        }
+    }
+
+    pub fn mode(&self) -> TermMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: TermMode) {
+        self.mode = mode;
+    }
+
+    /// Writes one row of a two-column table, with the label padded so that the values line up.
+    pub fn write_field(&mut self, label: &str, value: impl fmt::Display) -> fmt::Result {
+        let padding = FIELD_LABEL_WIDTH.saturating_sub(label.len() + 1);
+        if self.mode.ansi {
+            self.serial.write_str(ANSI_BOLD)?;
+            write!(self, "{}:", label)?;
+            self.serial.write_str(ANSI_RESET)?;
+        } else {
+            write!(self, "{}:", label)?;
+        }
+        writeln!(self, "{:padding$} {}", "", value, padding = padding)
+    }
+
+    /// Reports that a command succeeded.
+    pub fn write_ok(&mut self) -> fmt::Result {
+        self.write_colored(ANSI_GREEN, format_args!("OK"))
+    }
+
+    /// Reports that a command failed.
+    pub fn write_error(&mut self, message: impl fmt::Display) -> fmt::Result {
+        self.write_colored(ANSI_RED, format_args!("ERROR: {}", message))
+    }
+
+    fn write_colored(&mut self, color: &str, line: fmt::Arguments) -> fmt::Result {
+        if self.mode.ansi {
+            self.serial.write_str(color)?;
+            self.write_fmt(line)?;
+            self.serial.write_str(ANSI_RESET)?;
+        } else {
+            self.write_fmt(line)?;
+        }
+        writeln!(self)
+    }
+
+    /// Lists every command, a page at a time.
+    pub fn write_help_summary(&mut self) -> fmt::Result {
+        writeln!(self, "Commands:")?;
+        let page = self.pager.start(COMMANDS);
+        self.write_help_page(page)
+    }
+
+    /// Describes a single command.
+    pub fn write_help(&mut self, command: &CommandSpec) -> fmt::Result {
+        writeln!(self, "Usage: {}", command.usage)?;
+        writeln!(self, "{}", command.details)
+    }
+
+    fn write_help_page(&mut self, page: &[CommandSpec]) -> fmt::Result {
+        for command in page {
+            writeln!(
+                self,
+                "  {:<width$}{}",
+                command.usage,
+                command.summary,
+                width = USAGE_WIDTH
+            )?;
+        }
+        if self.pager.is_paused() {
+            self.serial.write_str(MORE_PROMPT)?;
+        }
+        Ok(())
+    }
+
+    /// Handles a key press at the `--more--` prompt.
+    fn continue_help(&mut self, byte: u8) {
+        let Some(page) = self.pager.key(byte) else {
+            return;
+        };
+        // Overwrite the prompt rather than leaving it in the scrollback.
+        let _ = self.serial.write_str("\r        \r");
+        let _ = self.write_help_page(page);
+    }
+}
+
+/// Stands in for the UART when the `serial-console` feature is off: nothing is ever read, and
+/// everything written is dropped.
+#[cfg(not(feature = "serial-console"))]
+pub struct Disconnected;
+
+#[cfg(not(feature = "serial-console"))]
+impl embedded_hal_nb::serial::ErrorType for Disconnected {
+    type Error = core::convert::Infallible;
+}
+
+#[cfg(not(feature = "serial-console"))]
+impl Read<u8> for Disconnected {
+    fn read(&mut self) -> embedded_hal_nb::nb::Result<u8, Self::Error> {
+        Err(embedded_hal_nb::nb::Error::WouldBlock)
+    }
+}
+
+#[cfg(not(feature = "serial-console"))]
+impl embedded_hal_nb::serial::Write<u8> for Disconnected {
+    fn write(&mut self, _word: u8) -> embedded_hal_nb::nb::Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> embedded_hal_nb::nb::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "serial-console"))]
+impl fmt::Write for Disconnected {
+    fn write_str(&mut self, _s: &str) -> fmt::Result {
+        Ok(())
+    }
+}
+
+impl<S: fmt::Write> fmt::Write for Console<S> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if !self.mode.crlf {
+            return self.serial.write_str(s);
+        }
+        for (i, part) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.serial.write_str("\r\n")?;
+            }
+            self.serial.write_str(part)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+// Saving things in the on-chip flash.
+//
+// The RP2040 runs its code straight out of flash, so nothing may be fetched from it while a
+// sector is being erased or programmed: no code, no constants and no interrupt handlers. The boot
+// ROM has routines to do the work. They are looked up beforehand and called from a function that
+// is copied to RAM at startup, with interrupts off. The ROM leaves the flash in its slowest read
+// mode, so afterwards boot2 is run again (from a copy in RAM) to restore the fast one.
+//
+// The last two sectors of the chip hold the settings, and memory.x keeps the firmware out of them.
+// Everything is written through `photopainter_core::storage::atomic_write`, so that a power cut
+// part way through doesn't lose what was there before.
+
+use photopainter_core::config::{self, DeviceConfig};
+use photopainter_core::storage::{self, Flash, PAGE_SIZE, SECTOR_SIZE};
+use rp2040_hal::rom_data;
+
+/// Where the flash appears in the address space.
+const XIP_BASE: usize = 0x1000_0000;
+
+const FLASH_SIZE: usize = 2048 * 1024;
+
+/// Offsets of the two sectors holding the settings, from the start of the flash.
+const CONFIG_SLOTS: [u32; 2] = [
+    (FLASH_SIZE - 2 * SECTOR_SIZE) as u32,
+    (FLASH_SIZE - SECTOR_SIZE) as u32,
+];
+
+/// Size of boot2, at the start of the flash.
+const BOOT2_SIZE: usize = 256;
+
+// Lets the ROM erase in 64K blocks where it can. The settings sector is smaller, so it is always
+// erased a sector at a time.
+const BLOCK_SIZE: u32 = 65536;
+const BLOCK_ERASE_CMD: u8 = 0xD8;
+
+/// Reads the settings, or returns the defaults if none have been saved.
+pub fn load_config() -> DeviceConfig {
+    let mut buffer = [0; config::RECORD_LEN];
+    storage::read(&mut OnChipFlash, CONFIG_SLOTS, &mut buffer)
+        .and_then(DeviceConfig::decode)
+        .unwrap_or_default()
+}
+
+/// Saves the settings, unless they are already the ones saved.
+#[cfg(not(feature = "kiosk"))]
+pub fn save_config(config: &DeviceConfig) -> Result<(), storage::StorageError> {
+    if load_config() == *config {
+        return Ok(());
+    }
+    storage::atomic_write(&mut OnChipFlash, CONFIG_SLOTS, &config.encode())
+}
+
+/// The flash chip the firmware runs from.
+struct OnChipFlash;
+
+impl Flash for OnChipFlash {
+    fn read(&mut self, offset: u32, buffer: &mut [u8]) {
+        // SAFETY: the whole flash is mapped for reading.
+        let flash = unsafe {
+            core::slice::from_raw_parts((XIP_BASE + offset as usize) as *const u8, buffer.len())
+        };
+        buffer.copy_from_slice(flash);
+    }
+
+    fn erase_sector(&mut self, offset: u32) {
+        write_flash(offset, None);
+    }
+
+    fn program_page(&mut self, offset: u32, data: &[u8; PAGE_SIZE]) {
+        write_flash(offset, Some(data));
+    }
+}
+
+/// The ROM routines, looked up while the flash can still be read.
+#[repr(C)]
+struct Rom {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+}
+
+fn write_flash(offset: u32, data: Option<&[u8; PAGE_SIZE]>) {
+    let rom = Rom {
+        connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+        flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+        flash_range_erase: rom_data::flash_range_erase::ptr(),
+        flash_range_program: rom_data::flash_range_program::ptr(),
+        flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+    };
+    let mut boot2 = [0u32; BOOT2_SIZE / 4];
+    // SAFETY: boot2 is at the start of the flash, which is mapped.
+    unsafe {
+        core::ptr::copy_nonoverlapping(XIP_BASE as *const u32, boot2.as_mut_ptr(), boot2.len());
+    }
+    let data = data.map_or(core::ptr::null(), |data| data.as_ptr());
+    cortex_m::interrupt::free(|_| {
+        // SAFETY: nothing else runs while interrupts are off, and the sector is reserved.
+        unsafe { write_flash_from_ram(&rom, boot2.as_ptr(), offset, data) }
+    });
+}
+
+/// Erases the sector at `offset` if `data` is null, or programs a page there from `data`.
+///
+/// This runs from RAM, and must not call anything in flash, including compiler intrinsics.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn write_flash_from_ram(rom: &Rom, boot2: *const u32, offset: u32, data: *const u8) {
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    if data.is_null() {
+        (rom.flash_range_erase)(offset, SECTOR_SIZE, BLOCK_SIZE, BLOCK_ERASE_CMD);
+    } else {
+        (rom.flash_range_program)(offset, data, PAGE_SIZE);
+    }
+    (rom.flash_flush_cache)();
+    // Thumb code, so the address is odd.
+    let boot2: unsafe extern "C" fn() = core::mem::transmute(boot2 as usize | 1);
+    boot2();
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
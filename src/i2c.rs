@@ -0,0 +1,116 @@
+// The I2C bus to the RTC.
+//
+// If the RP2040 is reset part way through a transaction, or a glitch makes the RTC miss a clock
+// edge, the RTC can be left holding SDA low while it waits for the rest of a byte. Every
+// transaction after that fails, including the one that sets the alarm. So the bus is cleared at
+// startup, and again whenever a transaction fails, before the RTC driver retries it.
+
+use defmt::*;
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{InputPin, OutputPin, PinState};
+use embedded_hal::i2c::{ErrorType, I2c, Operation};
+use fugit::{HertzU32, RateExtU32};
+use rp2040_hal::gpio::bank0::{Gpio14, Gpio15};
+use rp2040_hal::gpio::{FunctionI2C, FunctionNull, InOutPin, Pin, PullDown, PullUp};
+use rp2040_hal::{self as hal, pac};
+
+type SdaPin = Pin<Gpio14, FunctionI2C, PullUp>;
+type SclPin = Pin<Gpio15, FunctionI2C, PullUp>;
+
+const FREQUENCY_KHZ: u32 = 400;
+
+// Half a clock period when clocking the bus by hand, at 100 kHz.
+const HALF_PERIOD_US: u32 = 5;
+
+/// I2C1 on GP14 (SDA) and GP15 (SCL).
+pub struct RtcBus {
+    /// Only `None` while the bus is being cleared.
+    i2c: Option<hal::I2C<pac::I2C1, (SdaPin, SclPin)>>,
+    resets: pac::RESETS,
+    system_clock: HertzU32,
+    timer: hal::Timer,
+}
+
+impl RtcBus {
+    /// Clears the bus, and then hands it to the I2C block. The bus needs `resets` to start the
+    /// I2C block again after clearing it, so set up everything else that needs it first.
+    pub fn new(
+        i2c: pac::I2C1,
+        sda: Pin<Gpio14, FunctionNull, PullDown>,
+        scl: Pin<Gpio15, FunctionNull, PullDown>,
+        resets: pac::RESETS,
+        system_clock: HertzU32,
+        timer: hal::Timer,
+    ) -> Self {
+        let mut bus = RtcBus {
+            i2c: None,
+            resets,
+            system_clock,
+            timer,
+        };
+        bus.start(i2c, sda.reconfigure(), scl.reconfigure());
+        bus
+    }
+
+    fn start(&mut self, i2c: pac::I2C1, sda: SdaPin, scl: SclPin) {
+        let (sda, scl) = clear_bus(sda, scl, &mut self.timer);
+        self.i2c = Some(hal::I2C::i2c1(
+            i2c,
+            sda,
+            scl,
+            FREQUENCY_KHZ.kHz(),
+            &mut self.resets,
+            self.system_clock,
+        ));
+    }
+}
+
+impl ErrorType for RtcBus {
+    type Error = hal::i2c::Error;
+}
+
+impl I2c for RtcBus {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let i2c = self.i2c.as_mut().unwrap();
+        let result = i2c.transaction(address, operations);
+        if result.is_err() {
+            warn!("I2C transaction failed; clearing the bus");
+            let (i2c, (sda, scl)) = self.i2c.take().unwrap().free(&mut self.resets);
+            self.start(i2c, sda, scl);
+        }
+        result
+    }
+}
+
+/// Frees the bus from a device that is holding it, by clocking SCL until the device releases SDA,
+/// and then sending a STOP so that it waits for a new transaction.
+fn clear_bus(sda: SdaPin, scl: SclPin, delay: &mut impl DelayNs) -> (SdaPin, SclPin) {
+    let mut sda = InOutPin::new(sda);
+    let mut scl = scl.into_push_pull_output_in_state(PinState::High);
+    // A device can be at most part way through a byte plus its ACK bit.
+    for _ in 0..9 {
+        if sda.is_high().unwrap() {
+            break;
+        }
+        scl.set_low().unwrap();
+        delay.delay_us(HALF_PERIOD_US);
+        scl.set_high().unwrap();
+        delay.delay_us(HALF_PERIOD_US);
+    }
+    if sda.is_low().unwrap() {
+        warn!("I2C bus is stuck");
+    }
+    // SDA rising while SCL is high.
+    scl.set_low().unwrap();
+    sda.set_low().unwrap();
+    delay.delay_us(HALF_PERIOD_US);
+    scl.set_high().unwrap();
+    delay.delay_us(HALF_PERIOD_US);
+    sda.set_high().unwrap();
+    delay.delay_us(HALF_PERIOD_US);
+    (sda.release(), scl.reconfigure())
+}
@@ -4,37 +4,305 @@
 #![no_std]
 #![no_main]
 
-mod rtc;
+mod console;
+mod flash;
+mod i2c;
+mod sdcard;
 
 use panic_probe as _;
+use photopainter_core::battery;
+use photopainter_core::config;
+#[cfg(not(feature = "kiosk"))]
+use photopainter_core::frame;
+#[cfg(not(feature = "kiosk"))]
+use photopainter_core::image;
+use photopainter_core::mode::{Event, State};
+use photopainter_core::power::{self, PowerControl, ShutdownReason, WakeRecord};
+use photopainter_core::rtc;
+use photopainter_core::scheduler::{self, DisplayMode, Schedule};
+#[cfg(not(feature = "kiosk"))]
+use photopainter_core::ymodem;
 
 use rp2040_hal as hal;
 
+use core::fmt::Write;
 use defmt::*;
 use defmt_rtt as _;
-use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal::digital::{InputPin, OutputPin, PinState};
 use embedded_hal_0_2::adc::OneShot;
+use embedded_hal_bus::spi::ExclusiveDevice;
+use embedded_sdmmc::{sdcard::DummyCsPin, BlockDevice, TimeSource};
 use fugit::RateExtU32;
+#[cfg(feature = "serial-console")]
+use hal::uart::{DataBits, StopBits, UartConfig, UartPeripheral};
 use hal::{
     clocks::{init_clocks_and_plls, Clock},
+    gpio::Interrupt,
     pac,
     sio::Sio,
     watchdog::Watchdog,
 };
 
-// Minimum power is 3.1V.
-const MIN_BATTERY_MILLIVOLTS: u32 = 3100;
+// Longest schedule file that is read; 24 entries with a comment on each fit easily.
+const SCHEDULE_FILE_MAX_BYTES: usize = 1024;
+
+// Baud rate of the serial console on GP0 (TX) and GP1 (RX).
+#[cfg(feature = "serial-console")]
+const CONSOLE_BAUD_RATE: u32 = 115_200;
+
+// How long UPLOAD waits for the sender before asking again, and how many times it asks. The first
+// wait is longer, to give time to start the transfer in the terminal.
+#[cfg(not(feature = "kiosk"))]
+const UPLOAD_RETRY_MS: u64 = 3_000;
+#[cfg(not(feature = "kiosk"))]
+const UPLOAD_START_RETRIES: u32 = 20;
+#[cfg(not(feature = "kiosk"))]
+const UPLOAD_MAX_RETRIES: u32 = 10;
+
+// How long PUSHFB waits for the next byte of the frame before giving up, and how long the line
+// has to be quiet afterwards before the rest of a failed transfer counts as drained.
+#[cfg(not(feature = "kiosk"))]
+const PUSHFB_TIMEOUT_MS: u64 = 5_000;
+#[cfg(not(feature = "kiosk"))]
+const PUSHFB_DRAIN_MS: u64 = 100;
+
+// SPI clock for the SD card. Cards have to be initialized at 400 kHz or less.
+const SD_INIT_BAUD_RATE: u32 = 400_000;
+const SD_BAUD_RATE: u32 = 12_500_000;
 
 #[link_section = ".boot2"]
 #[used]
 pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_GENERIC_03H;
 
+// Program information for `picotool info`.
+#[link_section = ".bi_entries"]
+#[used]
+pub static PICOTOOL_ENTRIES: [rp_binary_info::EntryAddr; 6] = [
+    rp_binary_info::rp_cargo_bin_name!(),
+    rp_binary_info::rp_cargo_version!(),
+    rp_binary_info::rp_cargo_homepage_url!(),
+    rp_binary_info::rp_program_build_attribute!(),
+    rp_binary_info::env!(
+        rp_binary_info::consts::TAG_RASPBERRY_PI,
+        rp_binary_info::consts::ID_RP_PROGRAM_BUILD_ATTRIBUTE,
+        "GIT_HASH"
+    ),
+    rp_binary_info::rp_binary_end!(__flash_binary_end),
+];
+
+extern "C" {
+    static __flash_binary_end: u32;
+}
+
+/// Converts a raw VBAT ADC reading to millivolts.
+fn adc_to_battery_millivolts(raw: u16) -> u32 {
+    // Some sort of voltage divider (10x?) at 3.3V reference, x1000 for mV, using a 12-bit ADC.
+    // XXXX for some reason, Waveshare uses a 3x multiplier in their code and it seems to work. Why?
+    raw as u32 * 10 * 3300 / (1 << 12)
+}
+
+/// Converts a raw internal temperature sensor reading to degrees Celsius.
+fn adc_to_temperature_celsius(raw: u16) -> i32 {
+    // 0.706V at 27C, with a slope of -1.721mV per degree.
+    let microvolts = raw as i32 * 3300 * 1000 / (1 << 12);
+    27 - (microvolts - 706_000) / 1721
+}
+
+/// The battery power switch on GP18 (high is enabled; low turns off the power).
+struct BatterySwitch<P>(P);
+
+impl<P: OutputPin> PowerControl for BatterySwitch<P> {
+    fn power_off(&mut self) {
+        self.0.set_low().unwrap();
+    }
+}
+
+/// Prints a planned wake-up time, along with the alarm register values that would produce it.
+fn write_alarm_plan(
+    console: &mut impl Write,
+    label: &str,
+    at: &rtc::DateTime,
+) -> core::fmt::Result {
+    let [seconds, minutes, hours, day, weekday] = rtc::alarm_registers(at);
+    writeln!(
+        console,
+        "{}: {} (alarm registers {:02X} {:02X} {:02X} {:02X} {:02X})",
+        label, at, seconds, minutes, hours, day, weekday
+    )
+}
+
+/// Reads the refresh schedule from the SD card, or returns the default one if there isn't a usable
+/// one.
+fn load_schedule<D: BlockDevice, T: TimeSource>(sd_card: &mut sdcard::SdCard<D, T>) -> Schedule {
+    let mut contents = heapless::Vec::<u8, SCHEDULE_FILE_MAX_BYTES>::new();
+    let mut fits = true;
+    if sd_card
+        .read(None, scheduler::SCHEDULE_FILE, |chunk| {
+            fits &= contents.extend_from_slice(chunk).is_ok();
+        })
+        .is_err()
+    {
+        info!("No schedule file; using the default schedule");
+        return Schedule::default();
+    }
+    if !fits {
+        warn!("Schedule file is too long; using the default schedule");
+        return Schedule::default();
+    }
+    Schedule::parse(&contents).unwrap_or_else(|e| {
+        warn!("Schedule file: {}; using the default schedule", e);
+        Schedule::default()
+    })
+}
+
+/// Receives a file over the console with YMODEM or XMODEM-1K, passing its contents to `write`.
+///
+/// Returns the size of the file.
+#[cfg(not(feature = "kiosk"))]
+fn receive_file<S, E: Format>(
+    console: &mut console::Console<S>,
+    timer: &hal::Timer,
+    write: &mut dyn FnMut(&[u8]) -> Result<(), E>,
+) -> Result<u32, &'static str>
+where
+    S: embedded_hal_nb::serial::Read<u8> + embedded_hal_nb::serial::Write<u8> + Write,
+{
+    let mut receiver = ymodem::Receiver::new();
+    let mut bytes = 0;
+    let mut silent_periods = 0;
+    let mut last_ms = timer.get_counter().ticks() / 1000;
+    console.write_bytes(&[ymodem::CRC_MODE]);
+    loop {
+        let now_ms = timer.get_counter().ticks() / 1000;
+        let Some(byte) = console.read_byte() else {
+            if now_ms - last_ms >= UPLOAD_RETRY_MS {
+                silent_periods += 1;
+                let limit = if receiver.started() {
+                    UPLOAD_MAX_RETRIES
+                } else {
+                    UPLOAD_START_RETRIES
+                };
+                if silent_periods > limit {
+                    console.write_bytes(&[ymodem::CAN, ymodem::CAN]);
+                    return Err("upload timed out");
+                }
+                console.write_bytes(receiver.timed_out());
+                last_ms = now_ms;
+            }
+            continue;
+        };
+        last_ms = now_ms;
+        silent_periods = 0;
+        match receiver.push(byte) {
+            None => {}
+            Some(ymodem::Response::Reply(reply)) => console.write_bytes(reply),
+            Some(ymodem::Response::Data(data)) => {
+                if let Err(e) = write(data) {
+                    error!("Upload failed: {}", e);
+                    console.write_bytes(&[ymodem::CAN, ymodem::CAN]);
+                    return Err("failed to write to the SD card");
+                }
+                bytes += data.len() as u32;
+                console.write_bytes(&[ymodem::ACK]);
+            }
+            Some(ymodem::Response::Finished) => {
+                console.write_bytes(&[ymodem::ACK]);
+                return Ok(bytes);
+            }
+            Some(ymodem::Response::Aborted) => {
+                console.write_bytes(&[ymodem::CAN, ymodem::CAN]);
+                return Err("upload cancelled");
+            }
+        }
+    }
+}
+
+/// Receives `len` bytes of frame buffer over the console, passing each completed row to
+/// `row_done`.
+///
+/// If the transfer fails, the rest of it is read and thrown away, so that it isn't taken for
+/// commands.
+#[cfg(not(feature = "kiosk"))]
+fn receive_frame<S>(
+    console: &mut console::Console<S>,
+    timer: &hal::Timer,
+    len: u32,
+    encoding: frame::Encoding,
+    mut row_done: impl FnMut(&[u8; image::FRAME_ROW_BYTES]),
+) -> Result<(), frame::FrameError>
+where
+    S: embedded_hal_nb::serial::Read<u8> + embedded_hal_nb::serial::Write<u8> + Write,
+{
+    let mut receiver = frame::FrameReceiver::new(encoding);
+    let mut last_ms = timer.get_counter().ticks() / 1000;
+    let mut received = 0;
+    let mut result = Ok(());
+    while received < len {
+        let now_ms = timer.get_counter().ticks() / 1000;
+        let Some(byte) = console.read_byte() else {
+            if now_ms - last_ms >= PUSHFB_TIMEOUT_MS {
+                return Err(frame::FrameError::TooShort);
+            }
+            continue;
+        };
+        last_ms = now_ms;
+        received += 1;
+        result = receiver.push(byte, &mut row_done);
+        if result.is_err() {
+            break;
+        }
+    }
+    if result.is_err() {
+        loop {
+            let now_ms = timer.get_counter().ticks() / 1000;
+            if console.read_byte().is_some() {
+                last_ms = now_ms;
+            } else if now_ms - last_ms >= PUSHFB_DRAIN_MS {
+                break;
+            }
+        }
+        return result;
+    }
+    receiver.finish()
+}
+
+/// Changes one of the settings and saves them in flash.
+#[cfg(not(feature = "kiosk"))]
+fn change_setting<S>(
+    console: &mut console::Console<S>,
+    settings: &mut config::DeviceConfig,
+    setting: config::Setting,
+) -> core::fmt::Result
+where
+    S: embedded_hal_nb::serial::Read<u8> + embedded_hal_nb::serial::Write<u8> + Write,
+{
+    match settings.with(setting) {
+        Some(changed) => {
+            info!("Settings: {}", changed);
+            *settings = changed;
+            match flash::save_config(settings) {
+                Ok(()) => console.write_ok(),
+                Err(e) => {
+                    error!("Saving the settings failed: {}", e);
+                    console.write_error("changed, but saving it in flash failed")
+                }
+            }
+        }
+        None => console.write_error("invalid value"),
+    }
+}
+
 #[rp2040_hal::entry]
 fn main() -> ! {
-    info!("Boot start");
+    info!(
+        "Boot start: {} {} ({})",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        env!("GIT_HASH")
+    );
 
     let mut pac = pac::Peripherals::take().unwrap();
-    let core = pac::CorePeripherals::take().unwrap();
+    let mut core = pac::CorePeripherals::take().unwrap();
     let mut watchdog = Watchdog::new(pac.WATCHDOG);
     let sio = Sio::new(pac.SIO);
 
@@ -52,6 +320,7 @@ fn main() -> ! {
     .unwrap();
 
     let mut delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
+    let timer = hal::Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
 
     let pins = hal::gpio::Pins::new(
         pac.IO_BANK0,
@@ -75,44 +344,117 @@ fn main() -> ! {
     // DEV_Digital_Write(EPD_POWER_EN, 1);	// EPD power on
     // DEV_Digital_Write(EPD_CS_PIN, 1);
 
-    // See https://github.com/rp-rs/rp-hal-boards/blob/main/boards/rp-pico/examples/pico_spi_sd_card.rs.
-    // spi_init(SD_SPI_PORT, 12500 * 1000);
-    // gpio_set_function(SD_CLK_PIN, GPIO_FUNC_SPI);
-    // gpio_set_function(SD_MOSI_PIN, GPIO_FUNC_SPI);
-    // gpio_set_function(SD_MISO_PIN, GPIO_FUNC_SPI);
-    // DEV_GPIO_Mode(SD_CS_PIN, 1);
+    // RTC alarm (low means it triggered)
+    let mut rtc_alarm = pins.gpio6.into_pull_up_input();
+    info!("Alarm triggered: {}", rtc_alarm.is_low().unwrap());
 
-    let sda_pin: hal::gpio::Pin<_, hal::gpio::FunctionI2C, _> = pins.gpio14.reconfigure();
-    let scl_pin: hal::gpio::Pin<_, hal::gpio::FunctionI2C, _> = pins.gpio15.reconfigure();
+    // USB bus power (high means there is power).
+    let mut vbus_state = pins.gpio24.into_floating_input();
 
-    let i2c = hal::I2C::i2c1(
-        pac.I2C1,
-        sda_pin,
-        scl_pin,
-        400.kHz(),
+    // On batteries the power is cut between refreshes, so an alarm that is still active at boot
+    // is what turned the frame on. Nothing survives the power cut (not even the watchdog scratch
+    // registers), so this is the only way to tell. The RTC is then known to be running and set up,
+    // so there is nothing for init_device to do.
+    let fast_boot = rtc_alarm.is_low().unwrap() && vbus_state.is_low().unwrap();
+    if fast_boot {
+        info!("Woken by the RTC alarm; skipping RTC init");
+    }
+
+    // microSD card on SPI0. The card needs CS toggled separately from the SPI transfers, so the
+    // SPI device gets a dummy CS pin and the card driver gets the real one.
+    let sd_spi = hal::Spi::<_, _, _, 8>::new(
+        pac.SPI0,
+        (
+            pins.gpio3.into_function::<hal::gpio::FunctionSpi>(),
+            pins.gpio4.into_function::<hal::gpio::FunctionSpi>(),
+            pins.gpio2.into_function::<hal::gpio::FunctionSpi>(),
+        ),
+    )
+    .init(
         &mut pac.RESETS,
-        &clocks.peripheral_clock,
+        clocks.peripheral_clock.freq(),
+        SD_INIT_BAUD_RATE.Hz(),
+        embedded_hal::spi::MODE_0,
     );
+    let sd_spi = ExclusiveDevice::new_no_delay(sd_spi, DummyCsPin).unwrap();
+    let sd_cs = pins.gpio5.into_push_pull_output_in_state(PinState::High);
+    let sd = embedded_sdmmc::SdCard::new(sd_spi, sd_cs, timer);
+    match sd.num_bytes() {
+        Ok(bytes) => {
+            info!("SD card: {} MB", bytes / 1_000_000);
+            sd.spi(|spi| {
+                spi.bus_mut()
+                    .set_baudrate(clocks.peripheral_clock.freq(), SD_BAUD_RATE.Hz())
+            });
+        }
+        Err(e) => info!("No SD card: {}", e),
+    }
 
-    let mut rtc = rtc::PCF85063::new(i2c);
-    rtc.init_device(&mut delay).unwrap();
-
-    // RTC alarm (low means it triggered)
-    let mut rtc_alarm = pins.gpio6.into_pull_up_input();
-    info!("Alarm triggered: {}", rtc_alarm.is_low().unwrap());
+    // Serial console on the spare UART0 pins, for frames where USB isn't reachable.
+    #[cfg(feature = "serial-console")]
+    let mut console = {
+        let tx_pin: hal::gpio::Pin<_, hal::gpio::FunctionUart, hal::gpio::PullNone> =
+            pins.gpio0.reconfigure();
+        // Pull RX up so that an unconnected pin reads as an idle line rather than noise.
+        let rx_pin: hal::gpio::Pin<_, hal::gpio::FunctionUart, hal::gpio::PullUp> =
+            pins.gpio1.reconfigure();
+        let uart = UartPeripheral::new(pac.UART0, (tx_pin, rx_pin), &mut pac.RESETS)
+            .enable(
+                UartConfig::new(CONSOLE_BAUD_RATE.Hz(), DataBits::Eight, None, StopBits::One),
+                clocks.peripheral_clock.freq(),
+            )
+            .unwrap();
+        console::Console::new(uart)
+    };
+    // Without the console, GP0 and GP1 are left unconfigured, and no commands ever arrive.
+    #[cfg(not(feature = "serial-console"))]
+    let mut console = console::Console::new(console::Disconnected);
 
     // Set up ADC, which is used to read the battery voltage.
     let mut adc = hal::Adc::new(pac.ADC, &mut pac.RESETS);
     let mut vbat_adc = hal::adc::AdcPin::new(pins.gpio29).unwrap();
 
+    // The RTC on I2C1. This has to come after everything else that needs RESETS, because the bus
+    // keeps it to restart the I2C block whenever the bus has to be cleared.
+    let i2c = i2c::RtcBus::new(
+        pac.I2C1,
+        pins.gpio14,
+        pins.gpio15,
+        pac.RESETS,
+        clocks.peripheral_clock.freq(),
+        timer,
+    );
+    let mut rtc = rtc::PCF85063::new(i2c, timer);
+    // init_device resets the RTC if its oscillator stopped, which clears its RAM, so grab the
+    // record of the last wake first.
+    let last_wake = rtc
+        .read_ram_byte()
+        .map(WakeRecord::from_byte)
+        .unwrap_or_default();
+    let battery_was_low = last_wake.battery_low;
+    // Rewritten as this wake goes on, so that the next one can see how it went.
+    let mut wake_record = last_wake;
+    if !fast_boot {
+        rtc.init_device().unwrap();
+        // Nothing is connected to CLKOUT, so don't waste battery driving it.
+        rtc.set_clkout(rtc::ClkoutFrequency::Disabled).unwrap();
+    }
+    let boot_time = rtc.get_datetime().ok();
+    match boot_time {
+        Some(now) => info!("RTC time: {}", now),
+        None => info!("RTC time is not set"),
+    }
+
+    let mut sd_card = sdcard::SdCard::new(sd, sdcard::Clock::new(boot_time, timer));
+
     // Activity LED (red).
     let mut activity_led = pins.gpio25.into_push_pull_output();
 
     // Power LED (green).
     let mut power_led = pins.gpio26.into_push_pull_output();
 
-    // Battery power control (high is enabled; low turns off the power).
-    let mut battery_enable = pins.gpio18.into_push_pull_output();
+    // Battery power control.
+    let mut battery_enable = BatterySwitch(pins.gpio18.into_push_pull_output());
 
     // User button (low is button pressed, or the auto-switch is enabled).
     let mut user_button = pins.gpio19.into_pull_up_input();
@@ -120,35 +462,24 @@ fn main() -> ! {
     // Battery charging indicator (low is charging; high is not charging).
     let mut charge_state = pins.gpio17.into_pull_up_input();
 
-    // USB bus power (high means there is power).
-    let mut vbus_state = pins.gpio24.into_floating_input();
-
     activity_led.set_low().unwrap();
     power_led.set_low().unwrap();
 
     // Connect the battery.
-    battery_enable.set_high().unwrap();
+    battery_enable.0.set_high().unwrap();
 
     delay.delay_ms(500);
     let battery: u16 = adc.read(&mut vbat_adc).unwrap();
-    // Some sort of voltage divider (10x?) at 3.3V reference, x1000 for mV, using a 12-bit ADC.
-    // XXXX for some reason, Waveshare uses a 3x multiplier in their code and it seems to work. Why?
-    let battery_millivolts = battery as u32 * 10 * 3300 / (1 << 12);
+    let battery_millivolts = adc_to_battery_millivolts(battery);
 
     info!("VBUS power: {}", vbus_state.is_high().unwrap());
     info!("Charging: {}", charge_state.is_low().unwrap());
     info!("voltage: {} mV", battery_millivolts);
 
-    // let mut temperature_sensor = adc.take_temp_sensor().unwrap();
-    // for i in 0..10 {
-    //     let temp_sens_adc_counts: i64 = adc.read(&mut temperature_sensor).unwrap();
-    //     info!("Temperature: {} cnts", temp_sens_adc_counts);
-    //     let temp_uv = temp_sens_adc_counts * 3300 * 1000 / (1 << 12);
-    //     info!("Temperature: {} uV", temp_uv);
-    //     let temperature = 27 - (temp_uv - 706 * 1000) * 581 / 1000 / 1000;
-    //     info!("Temperature: {}", temperature);
-    //     delay.delay_ms(100);
-    // }
+    let mut temperature_sensor = adc.take_temp_sensor().unwrap();
+    let temperature: u16 = adc.read(&mut temperature_sensor).unwrap();
+
+    info!("temperature: {} C", adc_to_temperature_celsius(temperature));
 
     // rtcRunAlarm(Time, alarmTime);  // RTC run alarm
 
@@ -168,56 +499,467 @@ fn main() -> ! {
     //     rtcRunAlarm(Time, alarmTime);  // RTC run alarm
     // }
 
-    info!("Init done");
-
-    if vbus_state.is_low().unwrap() {
-        info!("Running on batteries");
-
-        if (battery_millivolts > MIN_BATTERY_MILLIVOLTS) {
-            // XXX run display; in the meantime, show the red light so we know we are here.
-            activity_led.set_high().unwrap();
-            delay.delay_ms(500);
-        } else {
-            info!("Low power");
-            // XXX disable alarm
-            for _ in 0..5 {
-                power_led.set_high().unwrap();
-                delay.delay_ms(200);
-                power_led.set_low().unwrap();
-                delay.delay_ms(100);
-            }
-        }
-    } else {
-        info!("Running off VBUS power");
-
-        // As long as it is plugged in, just keep looping.
-        while vbus_state.is_high().unwrap() {
-            if charge_state.is_low().unwrap() {
-                // Charging.
-                power_led.set_high().unwrap();
-            } else {
-                // Not charging.
-                power_led.set_low().unwrap();
-            }
+    info!(
+        "Init done in {} ms ({} boot)",
+        timer.get_counter().ticks() / 1000,
+        if fast_boot { "fast" } else { "full" }
+    );
 
-            if user_button.is_low().unwrap() {
-                // TODO: also handle RTC when on USB power: `|| rtc_alarm.is_low().unwrap() {`.
-                // xxx run display; in the meantime, show the red light so we know we are here.
+    // Kiosk builds can't change the settings.
+    #[cfg_attr(feature = "kiosk", allow(unused_mut))]
+    let mut settings = flash::load_config();
+    info!("Settings: {}", settings);
+    let card_schedule = load_schedule(&mut sd_card);
+    // SET can change the wake time and the display mode.
+    #[cfg_attr(feature = "kiosk", allow(unused_mut))]
+    let mut schedule = settings.schedule(&card_schedule);
+    for entry in schedule.entries() {
+        info!("Scheduled refresh: {}", entry);
+    }
+    let mut charge_monitor = battery::ChargeMonitor::new();
+    let mut last_full_charge = None;
+    let mut idle_monitor = console::IdleMonitor::new();
+
+    // Set by CheckPower, which always comes first.
+    let mut battery_level = battery::Level::Ok;
+    let mut state = State::CheckPower;
+    let shutdown_reason = loop {
+        let event = match state {
+            State::CheckPower => {
+                let on_usb = vbus_state.is_high().unwrap();
+                if on_usb {
+                    info!("Running off VBUS power");
+                } else {
+                    info!("Running on batteries");
+                }
+                battery_level = settings.battery.level(battery_millivolts, battery_was_low);
+                info!(
+                    "Battery level: {} (was low: {})",
+                    battery_level, battery_was_low
+                );
+                if battery_level == battery::Level::Warn {
+                    // Still enough to refresh, but let whoever is looking know it needs charging.
+                    for _ in 0..3 {
+                        power_led.set_high().unwrap();
+                        delay.delay_ms(100);
+                        power_led.set_low().unwrap();
+                        delay.delay_ms(400);
+                    }
+                }
+                let battery_low = battery_level == battery::Level::Low;
+                wake_record.battery_low = battery_low;
+                if rtc.write_ram_byte(wake_record.to_byte()).is_err() {
+                    error!("Failed to save the battery state");
+                }
+                Event::Power {
+                    on_usb,
+                    battery_low,
+                }
+            }
+            State::Render => {
+                // Without the time, there's no telling which entry woke us up.
+                let mode = rtc.get_datetime().map_or(
+                    settings.display_mode.unwrap_or(DisplayMode::Slideshow),
+                    |now| schedule.current(&now),
+                );
+                info!("Display mode: {}", mode);
+                // XXX draw `mode`, turned by `settings.rotation`, once there is a display driver.
+                // The slideshow would then pick the next image with photopainter_core::slideshow,
+                // and only save its index once it is on the panel. In the meantime, show the red
+                // light so we know we are here.
                 activity_led.set_high().unwrap();
-                info!("Button pushed");
                 delay.delay_ms(500);
                 activity_led.set_low().unwrap();
+                Event::Rendered
+            }
+            State::Schedule => {
+                let on_usb = vbus_state.is_high().unwrap();
+                if on_usb {
+                    // Keep to the schedule even if the frame never leaves USB power. Re-arming the
+                    // alarm also clears it if it just fired.
+                    power::schedule_next_refresh(&mut rtc, &schedule);
+                }
+                // On batteries, the next wake-up is programmed on the way out.
+                Event::Scheduled { on_usb }
+            }
+            State::ConsoleIdle if vbus_state.is_low().unwrap() => Event::UsbDisconnected,
+            State::ConsoleIdle => {
+                let charging = charge_state.is_low().unwrap();
+                // The power LED shows whether the battery is charging.
+                power_led.set_state(charging.into()).unwrap();
+                if charge_monitor.update(charging) {
+                    let battery: u16 = adc.read(&mut vbat_adc).unwrap();
+                    let full_charge = battery::FullCharge {
+                        at: rtc.get_datetime().ok(),
+                        millivolts: adc_to_battery_millivolts(battery),
+                    };
+                    info!("Charge complete: {}", full_charge);
+                    last_full_charge = Some(full_charge);
+                }
+
+                // Only SLEEPUNTIL changes it, and kiosk builds leave that out.
+                #[cfg_attr(feature = "kiosk", allow(unused_mut))]
+                let mut event = if rtc_alarm.is_low().unwrap() {
+                    info!("RTC alarm");
+                    Event::RefreshRequested
+                } else if user_button.is_low().unwrap() {
+                    info!("Button pushed");
+                    Event::RefreshRequested
+                } else {
+                    Event::Idle
+                };
+
+                let now_ms = timer.get_counter().ticks() / 1000;
+                let command = console.poll();
+                if command.is_some() {
+                    idle_monitor.activity(now_ms);
+                }
+                match command {
+                    Some(Ok(console::Command::Help(None))) => {
+                        console.write_help_summary().unwrap();
+                    }
+                    Some(Ok(console::Command::Help(Some(command)))) => {
+                        console.write_help(command).unwrap();
+                    }
+                    Some(Ok(console::Command::Status)) => {
+                        let battery: u16 = adc.read(&mut vbat_adc).unwrap();
+                        let temperature: u16 = adc.read(&mut temperature_sensor).unwrap();
+                        let millivolts = adc_to_battery_millivolts(battery);
+                        let celsius = adc_to_temperature_celsius(temperature);
+                        console
+                            .write_field("VBUS power", vbus_state.is_high().unwrap())
+                            .unwrap();
+                        console
+                            .write_field("Charging", charge_state.is_low().unwrap())
+                            .unwrap();
+                        console
+                            .write_field("Battery", format_args!("{} mV", millivolts))
+                            .unwrap();
+                        console.write_field("Battery level", battery_level).unwrap();
+                        console
+                            .write_field("Temperature", format_args!("{} C", celsius))
+                            .unwrap();
+                        console.write_field("Last wake", last_wake).unwrap();
+                    }
+                    Some(Ok(console::Command::Temp)) => {
+                        let temperature: u16 = adc.read(&mut temperature_sensor).unwrap();
+                        let celsius = adc_to_temperature_celsius(temperature);
+                        console
+                            .write_field("Temperature", format_args!("{} C", celsius))
+                            .unwrap();
+                    }
+                    Some(Ok(console::Command::Time)) => match rtc.get_datetime() {
+                        Ok(now) => writeln!(console, "{}", now).unwrap(),
+                        Err(_) => console.write_error("RTC time is not set").unwrap(),
+                    },
+                    #[cfg(not(feature = "kiosk"))]
+                    Some(Ok(console::Command::SetTime(datetime))) => {
+                        match rtc.set_datetime(&datetime) {
+                            Ok(()) => console.write_ok().unwrap(),
+                            Err(_) => console.write_error("failed to set RTC time").unwrap(),
+                        }
+                    }
+                    Some(Ok(console::Command::Battery)) => {
+                        let battery: u16 = adc.read(&mut vbat_adc).unwrap();
+                        let millivolts = adc_to_battery_millivolts(battery);
+                        let thresholds = settings.battery;
+                        console
+                            .write_field("Battery", format_args!("{} mV", millivolts))
+                            .unwrap();
+                        console
+                            .write_field(
+                                "Shutdown",
+                                format_args!("{} mV", thresholds.shutdown_millivolts),
+                            )
+                            .unwrap();
+                        console
+                            .write_field("Warn", format_args!("{} mV", thresholds.warn_millivolts))
+                            .unwrap();
+                        console
+                            .write_field(
+                                "Hysteresis",
+                                format_args!("{} mV", thresholds.hysteresis_millivolts),
+                            )
+                            .unwrap();
+                        let label = "Last full charge";
+                        match last_full_charge {
+                            None => console.write_field(label, "not seen since power-on"),
+                            Some(charge) => {
+                                let days_ago = rtc
+                                    .get_datetime()
+                                    .ok()
+                                    .and_then(|now| charge.days_ago(&now));
+                                match days_ago {
+                                    Some(days) => console.write_field(
+                                        label,
+                                        format_args!("{} mV, {} days ago", charge.millivolts, days),
+                                    ),
+                                    None => console.write_field(
+                                        label,
+                                        format_args!("{} mV", charge.millivolts),
+                                    ),
+                                }
+                            }
+                        }
+                        .unwrap();
+                    }
+                    #[cfg(not(feature = "kiosk"))]
+                    Some(Ok(console::Command::SetBattery(threshold, millivolts))) => {
+                        let setting = config::Setting::Battery(threshold, millivolts);
+                        change_setting(&mut console, &mut settings, setting).unwrap();
+                    }
+                    #[cfg(not(feature = "kiosk"))]
+                    Some(Ok(console::Command::SleepUntil(wake))) => match rtc.get_datetime() {
+                        Ok(now) => match power::check_wake_time(&now, &wake) {
+                            Ok(()) => {
+                                writeln!(console, "Sleeping until {}", wake).unwrap();
+                                event = Event::SleepRequested(wake);
+                            }
+                            Err(e) => console.write_error(e).unwrap(),
+                        },
+                        Err(_) => console.write_error("RTC time is not set").unwrap(),
+                    },
+                    Some(Ok(console::Command::Plan(sleep_until))) => match rtc.get_datetime() {
+                        Ok(now) => {
+                            writeln!(console, "Now: {}", now).unwrap();
+                            match sleep_until {
+                                None => {
+                                    if let Some((wake, entry)) = schedule.next(&now) {
+                                        writeln!(console, "Next refresh shows: {}", entry.mode)
+                                            .unwrap();
+                                        write_alarm_plan(&mut console, "Next refresh", &wake)
+                                            .unwrap();
+                                    }
+                                }
+                                Some(wake) => match power::check_wake_time(&now, &wake) {
+                                    Ok(()) => {
+                                        write_alarm_plan(&mut console, "SLEEPUNTIL", &wake).unwrap()
+                                    }
+                                    Err(e) => console.write_error(e).unwrap(),
+                                },
+                            }
+                        }
+                        Err(_) => console.write_error("RTC time is not set").unwrap(),
+                    },
+                    Some(Ok(console::Command::Term(None))) => {
+                        let mode = console.mode();
+                        let on_off = |on| if on { "ON" } else { "OFF" };
+                        console.write_field("ECHO", on_off(mode.echo)).unwrap();
+                        console.write_field("CRLF", on_off(mode.crlf)).unwrap();
+                        console.write_field("ANSI", on_off(mode.ansi)).unwrap();
+                    }
+                    Some(Ok(console::Command::Term(Some((setting, on))))) => {
+                        let mut mode = console.mode();
+                        mode.set(setting, on);
+                        console.set_mode(mode);
+                        console.write_ok().unwrap();
+                    }
+                    Some(Ok(console::Command::Monitor)) => match idle_monitor.interval_seconds() {
+                        Some(seconds) => console
+                            .write_field("Interval", format_args!("{} s", seconds))
+                            .unwrap(),
+                        None => console.write_field("Interval", "off").unwrap(),
+                    },
+                    Some(Ok(console::Command::SetMonitor(seconds))) => {
+                        idle_monitor.set_interval(seconds, now_ms);
+                        console.write_ok().unwrap();
+                    }
+                    Some(Ok(console::Command::Ls(dir))) => {
+                        let dir = dir.as_ref().map(|dir| dir.as_str());
+                        let listed = sd_card.list(dir, |entry| {
+                            if entry.attributes.is_volume() {
+                                return;
+                            }
+                            let name = sdcard::file_name(entry);
+                            if entry.attributes.is_directory() {
+                                console.write_field(&name, "<DIR>").unwrap();
+                            } else {
+                                console
+                                    .write_field(&name, format_args!("{} bytes", entry.size))
+                                    .unwrap();
+                            }
+                        });
+                        if let Err(e) = listed {
+                            console
+                                .write_error(format_args!("SD card: {:?}", e))
+                                .unwrap();
+                        }
+                    }
+                    Some(Ok(console::Command::Cat(name))) => {
+                        let read = sd_card.read(name.dir(), name.name(), |chunk| {
+                            for &byte in chunk {
+                                // Keep binary files from upsetting the terminal.
+                                let c = match byte {
+                                    b'\r' => continue,
+                                    b'\n' | b'\t' | b' '..=b'~' => byte as char,
+                                    _ => '.',
+                                };
+                                console.write_char(c).unwrap();
+                            }
+                        });
+                        match read {
+                            Ok(()) => writeln!(console).unwrap(),
+                            Err(e) => console
+                                .write_error(format_args!("SD card: {:?}", e))
+                                .unwrap(),
+                        }
+                    }
+                    #[cfg(not(feature = "kiosk"))]
+                    Some(Ok(console::Command::Upload(name))) => {
+                        writeln!(
+                            console,
+                            "Start the YMODEM or XMODEM-1K upload now (Ctrl-X twice to cancel)"
+                        )
+                        .unwrap();
+                        let received = sd_card.create(name.dir(), name.name(), |write| {
+                            receive_file(&mut console, &timer, write)
+                        });
+                        match received {
+                            Ok(Ok(bytes)) => {
+                                info!("Uploaded {} ({} bytes)", name.as_str(), bytes);
+                                console.write_ok().unwrap();
+                            }
+                            Ok(Err(e)) => {
+                                // Don't leave a partial file behind.
+                                let _ = sd_card.delete(name.dir(), name.name());
+                                console.write_error(e).unwrap();
+                            }
+                            Err(e) => console
+                                .write_error(format_args!("SD card: {:?}", e))
+                                .unwrap(),
+                        }
+                    }
+                    #[cfg(not(feature = "kiosk"))]
+                    Some(Ok(console::Command::PushFb(len, encoding))) => {
+                        writeln!(console, "Send the {} bytes of the frame now", len).unwrap();
+                        // XXX send each row to the display once there is a driver for it.
+                        let received =
+                            receive_frame(&mut console, &timer, len, encoding, |_row| {});
+                        match received {
+                            Ok(()) => {
+                                info!("Frame received ({} bytes, {})", len, encoding);
+                                console.write_error("display not supported yet").unwrap();
+                            }
+                            Err(e) => console.write_error(e).unwrap(),
+                        }
+                    }
+                    Some(Ok(console::Command::Dither)) => {
+                        console.write_field("Dither", settings.dither_mode).unwrap();
+                    }
+                    #[cfg(not(feature = "kiosk"))]
+                    Some(Ok(console::Command::SetDither(mode))) => {
+                        let setting = config::Setting::Dither(mode);
+                        change_setting(&mut console, &mut settings, setting).unwrap();
+                    }
+                    #[cfg(not(feature = "kiosk"))]
+                    Some(Ok(console::Command::Set(setting))) => {
+                        change_setting(&mut console, &mut settings, setting).unwrap();
+                        let changed = settings.schedule(&card_schedule);
+                        if changed != schedule {
+                            schedule = changed;
+                            power::schedule_next_refresh(&mut rtc, &schedule);
+                        }
+                    }
+                    Some(Ok(console::Command::Get(key))) => {
+                        console.write_field(key.name(), settings.get(key)).unwrap();
+                    }
+                    Some(Ok(console::Command::ShowConfig)) => {
+                        for key in config::Key::ALL {
+                            console.write_field(key.name(), settings.get(key)).unwrap();
+                        }
+                    }
+                    #[cfg(not(feature = "kiosk"))]
+                    Some(Ok(console::Command::Reset)) => {
+                        writeln!(console, "Resetting").unwrap();
+                        delay.delay_ms(10);
+                        cortex_m::peripheral::SCB::sys_reset();
+                    }
+                    #[cfg(not(feature = "kiosk"))]
+                    Some(Ok(console::Command::Dfu)) => {
+                        writeln!(console, "Rebooting into the USB bootloader").unwrap();
+                        delay.delay_ms(10);
+                        hal::rom_data::reset_to_usb_boot(0, 0);
+                    }
+                    Some(Err(e)) => console.write_error(e).unwrap(),
+                    None => {}
+                }
+
+                if idle_monitor.poll(now_ms) {
+                    let battery: u16 = adc.read(&mut vbat_adc).unwrap();
+                    match rtc.get_datetime() {
+                        Ok(now) => core::write!(console, "MONITOR time={}", now),
+                        Err(_) => core::write!(console, "MONITOR time=unset"),
+                    }
+                    .unwrap();
+                    writeln!(
+                        console,
+                        " battery_mv={} charging={} state={:?}",
+                        adc_to_battery_millivolts(battery),
+                        charge_state.is_low().unwrap() as u8,
+                        state
+                    )
+                    .unwrap();
+                }
+
+                // Poll often enough that pasted input doesn't overflow the UART FIFO.
+                delay.delay_ms(10);
+                event
             }
+            State::Shutdown(reason) => {
+                if reason == ShutdownReason::LowBattery {
+                    info!("Low power");
+                    for _ in 0..5 {
+                        power_led.set_high().unwrap();
+                        delay.delay_ms(200);
+                        power_led.set_low().unwrap();
+                        delay.delay_ms(100);
+                    }
+                }
+                break reason;
+            }
+        };
 
-            delay.delay_ms(200);
+        let next = state.next(event);
+        if next != state {
+            info!("{} -> {}", state, next);
         }
+        state = next;
+    };
+
+    // Time awake is what the battery life depends on, so log it to compare the boot paths, and
+    // keep it for STATUS on the next wake.
+    let awake_millis = timer.get_counter().ticks() / 1000;
+    info!(
+        "Awake for {} ms ({} boot)",
+        awake_millis,
+        if fast_boot { "fast" } else { "full" }
+    );
+    wake_record.fast_boot = fast_boot;
+    wake_record.set_awake_millis(awake_millis);
+    if rtc.write_ram_byte(wake_record.to_byte()).is_err() {
+        error!("Failed to save the awake time");
     }
-
-    // Disconnect the battery.
-    battery_enable.set_low().unwrap();
-
+    power::shutdown(&mut rtc, &mut battery_enable, shutdown_reason, &schedule);
+
+    // If we are still running, something else is powering the board (e.g. USB was plugged back
+    // in). Sleep until the button is pressed or the RTC alarm fires, and then start over.
+    info!("Still powered; waiting for the button or the RTC alarm");
+    user_button.clear_interrupt(Interrupt::EdgeLow);
+    user_button.set_interrupt_enabled(Interrupt::EdgeLow, true);
+    rtc_alarm.clear_interrupt(Interrupt::EdgeLow);
+    rtc_alarm.set_interrupt_enabled(Interrupt::EdgeLow, true);
+    // The GPIO interrupt is never unmasked in the NVIC, so no handler runs; SEVONPEND makes it
+    // pending, which is enough to wake up from WFE.
+    core.SCB.set_sevonpend();
+    pac::NVIC::unpend(pac::Interrupt::IO_IRQ_BANK0);
     loop {
-        // Should be unreachable.
-        delay.delay_ms(1000);
+        cortex_m::asm::wfe();
+        if user_button.interrupt_status(Interrupt::EdgeLow)
+            || rtc_alarm.interrupt_status(Interrupt::EdgeLow)
+        {
+            info!("Woken up; restarting");
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+        pac::NVIC::unpend(pac::Interrupt::IO_IRQ_BANK0);
     }
 }
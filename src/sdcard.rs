@@ -0,0 +1,157 @@
+// Files on the microSD card.
+//
+// The card is on SPI0 and formatted FAT16 or FAT32, like the stock firmware expects. Files are
+// either in the root directory or one level below it, and only one is open at a time.
+
+use core::fmt::Write;
+use embedded_sdmmc::{
+    BlockDevice, DirEntry, Mode, RawDirectory, RawFile, TimeSource, Timestamp, VolumeIdx,
+    VolumeManager,
+};
+
+use photopainter_core::rtc::DateTime;
+
+pub type Error<E> = embedded_sdmmc::Error<E>;
+
+/// Timestamps for new and modified files: the RTC time read at boot plus the time since.
+pub struct Clock {
+    /// `None` if the RTC wasn't set at boot.
+    boot: Option<DateTime>,
+    timer: rp2040_hal::Timer,
+}
+
+impl Clock {
+    pub fn new(boot: Option<DateTime>, timer: rp2040_hal::Timer) -> Self {
+        Clock { boot, timer }
+    }
+}
+
+impl TimeSource for Clock {
+    fn get_timestamp(&self) -> Timestamp {
+        let seconds = (self.timer.get_counter().ticks() / 1_000_000) as u32;
+        // Files written with the clock unset get the RTC's earliest date.
+        let now = self
+            .boot
+            .and_then(|boot| boot.checked_add_seconds(seconds))
+            .unwrap_or_else(|| DateTime::from_timestamp(0));
+        Timestamp {
+            year_since_1970: (now.year - 1970) as u8,
+            zero_indexed_month: now.month - 1,
+            zero_indexed_day: now.day - 1,
+            hours: now.hours,
+            minutes: now.minutes,
+            seconds: now.seconds,
+        }
+    }
+}
+
+/// The first FAT volume on the card.
+pub struct SdCard<D: BlockDevice, T: TimeSource> {
+    volume_mgr: VolumeManager<D, T>,
+}
+
+impl<D: BlockDevice, T: TimeSource> SdCard<D, T> {
+    pub fn new(device: D, time_source: T) -> Self {
+        SdCard {
+            volume_mgr: VolumeManager::new(device, time_source),
+        }
+    }
+
+    /// Calls `f` for each entry in `dir`, or in the root directory if that's `None`.
+    pub fn list(
+        &mut self,
+        dir: Option<&str>,
+        f: impl FnMut(&DirEntry),
+    ) -> Result<(), Error<D::Error>> {
+        self.in_dir(dir, |volume_mgr, dir| volume_mgr.iterate_dir(dir, f))
+    }
+
+    /// Calls `f` with successive chunks of the file `name` in `dir`.
+    pub fn read(
+        &mut self,
+        dir: Option<&str>,
+        name: &str,
+        mut f: impl FnMut(&[u8]),
+    ) -> Result<(), Error<D::Error>> {
+        self.in_dir(dir, |volume_mgr, dir| {
+            let file = volume_mgr.open_file_in_dir(dir, name, Mode::ReadOnly)?;
+            let result = read_to_end(volume_mgr, file, &mut f);
+            close(result, volume_mgr.close_file(file))
+        })
+    }
+
+    /// Creates the file `name` in `dir`, or empties it if it exists, and then calls `f` with a
+    /// function that appends to it.
+    #[cfg(not(feature = "kiosk"))]
+    pub fn create<R>(
+        &mut self,
+        dir: Option<&str>,
+        name: &str,
+        f: impl FnOnce(&mut dyn FnMut(&[u8]) -> Result<(), Error<D::Error>>) -> R,
+    ) -> Result<R, Error<D::Error>> {
+        self.in_dir(dir, |volume_mgr, dir| {
+            let file = volume_mgr.open_file_in_dir(dir, name, Mode::ReadWriteCreateOrTruncate)?;
+            let result = f(&mut |data| volume_mgr.write(file, data));
+            close(Ok(result), volume_mgr.close_file(file))
+        })
+    }
+
+    /// Deletes the file `name` in `dir`.
+    #[cfg(not(feature = "kiosk"))]
+    pub fn delete(&mut self, dir: Option<&str>, name: &str) -> Result<(), Error<D::Error>> {
+        self.in_dir(dir, |volume_mgr, dir| {
+            volume_mgr.delete_file_in_dir(dir, name)
+        })
+    }
+
+    /// Opens `dir` (or the root directory) for `f`, and closes it again afterwards.
+    ///
+    /// This uses the raw handles, because the RAII ones panic if closing fails, e.g. when the
+    /// card is pulled out while a file is being written.
+    fn in_dir<R>(
+        &mut self,
+        dir: Option<&str>,
+        f: impl FnOnce(&mut VolumeManager<D, T>, RawDirectory) -> Result<R, Error<D::Error>>,
+    ) -> Result<R, Error<D::Error>> {
+        let volume_mgr = &mut self.volume_mgr;
+        let volume = volume_mgr.open_raw_volume(VolumeIdx(0))?;
+        let result = volume_mgr.open_root_dir(volume).and_then(|root| {
+            let result = match dir {
+                Some(name) => volume_mgr.open_dir(root, name).and_then(|dir| {
+                    let result = f(volume_mgr, dir);
+                    close(result, volume_mgr.close_dir(dir))
+                }),
+                None => f(volume_mgr, root),
+            };
+            close(result, volume_mgr.close_dir(root))
+        });
+        close(result, volume_mgr.close_volume(volume))
+    }
+}
+
+/// The 8.3 name of a directory entry, as text.
+pub fn file_name(entry: &DirEntry) -> heapless::String<12> {
+    let mut name = heapless::String::new();
+    // 8.3 names always fit.
+    core::write!(name, "{}", entry.name).unwrap();
+    name
+}
+
+fn read_to_end<D: BlockDevice, T: TimeSource>(
+    volume_mgr: &mut VolumeManager<D, T>,
+    file: RawFile,
+    f: &mut impl FnMut(&[u8]),
+) -> Result<(), Error<D::Error>> {
+    let mut buffer = [0; 64];
+    while !volume_mgr.file_eof(file)? {
+        let len = volume_mgr.read(file, &mut buffer)?;
+        f(&buffer[..len]);
+    }
+    Ok(())
+}
+
+/// Combines the result of using a handle with the result of closing it afterwards.
+fn close<R, E>(result: Result<R, E>, closed: Result<(), E>) -> Result<R, E> {
+    let value = result?;
+    closed.map(|()| value)
+}